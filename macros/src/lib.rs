@@ -0,0 +1,221 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Procedural macro frontend for the Idol compiler.
+//!
+//! These macros are an alternative to generating code from a `build.rs` and
+//! pulling it in with `include!`. They run the same generators as
+//! `idol::client::build_client_stub` and `idol::server::build_server_support`,
+//! but expand in place:
+//!
+//! ```ignore
+//! idol_macros::client!("../../idl/my-interface.idol");
+//! idol_macros::server!("../../idl/my-interface.idol", InOrder);
+//! ```
+//!
+//! Relative paths are resolved against the directory containing the invoking
+//! crate's `Cargo.toml`, which is where a `build.rs` would have found them.
+//!
+//! Both macros accept optional trailing `userlib = "..."`, `zerocopy = "..."`
+//! and `idol_runtime = "..."` settings, which change how generated code refers
+//! to those crates, as with the corresponding `idol::Generator` settings. They
+//! also accept `handshake = true`, `reflection = true`, `api_trait = true`,
+//! `mock = true`, `fuzz_harness = true` and `counters = true`, which turn on
+//! the `idol::Generator` settings of those names.
+
+use idol::{syntax, Generator};
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use std::path::PathBuf;
+use syn::parse::{Parse, ParseStream};
+use syn::{parse_macro_input, Ident, Lit, LitStr, Token};
+
+/// Arguments to `client!`: the path to the interface definition, and any
+/// settings.
+struct ClientArgs {
+    source: LitStr,
+    settings: Vec<(Ident, Lit)>,
+}
+
+impl Parse for ClientArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let source = input.parse()?;
+        let settings = parse_settings(input)?;
+        Ok(Self { source, settings })
+    }
+}
+
+/// Arguments to `server!`: the path to the interface definition, the name of a
+/// `ServerStyle` variant, and any settings.
+struct ServerArgs {
+    source: LitStr,
+    style: Ident,
+    settings: Vec<(Ident, Lit)>,
+}
+
+impl Parse for ServerArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let source = input.parse()?;
+        input.parse::<Token![,]>()?;
+        let style = input.parse()?;
+        let settings = parse_settings(input)?;
+        Ok(Self {
+            source,
+            style,
+            settings,
+        })
+    }
+}
+
+/// Parses the `, name = value` settings that may follow the required macro
+/// arguments, allowing a trailing comma.
+fn parse_settings(input: ParseStream) -> syn::Result<Vec<(Ident, Lit)>> {
+    let mut settings = vec![];
+    while !input.is_empty() {
+        input.parse::<Token![,]>()?;
+        if input.is_empty() {
+            break;
+        }
+        let name = input.parse()?;
+        input.parse::<Token![=]>()?;
+        settings.push((name, input.parse()?));
+    }
+    Ok(settings)
+}
+
+/// Creates a `Generator` with the settings given to a macro.
+fn configure(settings: &[(Ident, Lit)]) -> syn::Result<Generator> {
+    let mut gen = Generator::new();
+    for (name, value) in settings {
+        gen = match name.to_string().as_str() {
+            "userlib" => gen.userlib_path(str_setting(value)?),
+            "zerocopy" => gen.zerocopy_path(str_setting(value)?),
+            "idol_runtime" => gen.idol_runtime_path(str_setting(value)?),
+            "handshake" => gen.handshake(bool_setting(value)?),
+            "reflection" => gen.reflection(bool_setting(value)?),
+            "api_trait" => gen.api_trait(bool_setting(value)?),
+            "mock" => gen.mock(bool_setting(value)?),
+            "fuzz_harness" => gen.fuzz_harness(bool_setting(value)?),
+            "counters" => gen.counters(bool_setting(value)?),
+            _ => {
+                return Err(syn::Error::new(
+                    name.span(),
+                    format!("unknown setting `{}`", name),
+                ))
+            }
+        };
+    }
+    Ok(gen)
+}
+
+/// Returns the value of a setting that takes a string.
+fn str_setting(value: &Lit) -> syn::Result<String> {
+    match value {
+        Lit::Str(s) => Ok(s.value()),
+        _ => Err(syn::Error::new(value.span(), "expected a string")),
+    }
+}
+
+/// Returns the value of a setting that takes a `bool`.
+fn bool_setting(value: &Lit) -> syn::Result<bool> {
+    match value {
+        Lit::Bool(b) => Ok(b.value),
+        _ => Err(syn::Error::new(value.span(), "expected `true` or `false`")),
+    }
+}
+
+/// Generates a client stub for the interface defined in the named file.
+///
+/// This expands to the same code that `idol::client::build_client_stub` would
+/// write into `OUT_DIR`.
+#[proc_macro]
+pub fn client(input: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(input as ClientArgs);
+    configure(&args.settings)
+        .and_then(|gen| {
+            expand(&args.source, |iface, _text, out| {
+                gen.generate_client_stub(iface, out)
+            })
+        })
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// Generates server support code for the interface defined in the named file,
+/// using the given server style.
+///
+/// This expands to the same code that `idol::server::build_server_support`
+/// would write into `OUT_DIR`.
+#[proc_macro]
+pub fn server(input: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(input as ServerArgs);
+    let style = match args.style.to_string().parse() {
+        Ok(style) => style,
+        Err(e) => {
+            return syn::Error::new(args.style.span(), e)
+                .into_compile_error()
+                .into()
+        }
+    };
+    configure(&args.settings)
+        .and_then(|gen| {
+            let gen = gen.server_style(style);
+            expand(&args.source, |iface, text, out| {
+                gen.generate_server_support(iface, text, out)
+            })
+        })
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// Common implementation of the macros: loads the interface named by `source`,
+/// runs `generate` over it, and turns the output back into tokens.
+///
+/// Any failure is reported as an error spanning the path literal.
+fn expand(
+    source: &LitStr,
+    generate: impl FnOnce(
+        &syntax::Interface,
+        &str,
+        &mut Vec<u8>,
+    ) -> Result<(), idol::Error>,
+) -> syn::Result<TokenStream2> {
+    let error = |msg: String| syn::Error::new(source.span(), msg);
+
+    let path = resolve(source)?;
+    let (iface, text) =
+        syntax::Interface::load(&path).map_err(|e| error(e.to_string()))?;
+
+    let mut out = vec![];
+    generate(&iface, &text, &mut out).map_err(|e| error(e.to_string()))?;
+    let code: TokenStream2 = String::from_utf8(out)
+        .map_err(|e| error(format!("generated code: {}", e)))?
+        .parse()
+        .map_err(|e| error(format!("generated code: {:?}", e)))?;
+
+    // Proc macros have no stable way to declare that they depend on a file.
+    // Including its bytes in an unused constant gets rustc to track it for us,
+    // so that edits to the interface trigger a rebuild.
+    let path = path
+        .to_str()
+        .ok_or_else(|| error(format!("non-UTF-8 path {}", path.display())))?;
+    Ok(quote! {
+        const _: &[u8] = include_bytes!(#path);
+        #code
+    })
+}
+
+/// Resolves the path in `source` relative to the invoking crate's manifest
+/// directory, unless it's already absolute.
+fn resolve(source: &LitStr) -> syn::Result<PathBuf> {
+    let path = PathBuf::from(source.value());
+    if path.is_absolute() {
+        return Ok(path);
+    }
+    let dir = std::env::var_os("CARGO_MANIFEST_DIR").ok_or_else(|| {
+        syn::Error::new(source.span(), "CARGO_MANIFEST_DIR is not set")
+    })?;
+    Ok(PathBuf::from(dir).join(path))
+}
@@ -0,0 +1,220 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Fuzzing of generated server stubs, using the simulated kernel in `sim`.
+//!
+//! Generated servers decode messages sent by untrusted tasks. `run` turns an
+//! arbitrary byte string into a message (operation, payload, reply buffer, and
+//! leases), sends it to a server running `dispatch`, and checks the response:
+//! the server must not crash, well-formed messages must reach the server
+//! implementation, and malformed ones must be rejected with a `ClientError`.
+//!
+//! The idol compiler generates the pieces `run` needs for an interface when
+//! asked to with `Generator::fuzz_harness`: a table of `OpShape`s, a server
+//! whose every operation fails with `ClientError::WentAway` (which the dispatch
+//! code never produces itself, so it shows that a message got through), and a
+//! `fuzz_<interface>(data)` function to call from a fuzz target.
+
+use crate::sim::{self, Lease, Sim};
+use crate::{ClientError, FIRST_RESERVED_OP};
+
+/// What a generated server expects of messages for one operation.
+#[derive(Copy, Clone, Debug)]
+pub struct OpShape {
+    /// Exact size of the argument message.
+    pub message_size: usize,
+    /// Smallest reply buffer the server accepts.
+    pub reply_size: usize,
+    /// Whether decoding the arguments can fail even when the message is the
+    /// right size, because an argument is converted with `FromPrimitive` or
+    /// has constraints.
+    pub fallible_args: bool,
+    /// Leases the operation takes, in order.
+    pub leases: &'static [LeaseShape],
+}
+
+/// What a generated server expects of one lease.
+#[derive(Copy, Clone, Debug)]
+pub struct LeaseShape {
+    pub read: bool,
+    pub write: bool,
+    /// Size of the leased type or, for slices, of one element.
+    pub element_size: usize,
+    /// Whether the leased type is a slice.
+    pub slice: bool,
+    /// Fewest elements a slice may have.
+    pub min_len: Option<u32>,
+    /// Most elements a slice may have.
+    pub max_len: Option<u32>,
+}
+
+impl LeaseShape {
+    fn accepts(&self, lease: &FuzzLease) -> bool {
+        if (self.read && !lease.read) || (self.write && !lease.write) {
+            return false;
+        }
+        if !self.slice {
+            return lease.data.len() == self.element_size;
+        }
+        if lease.data.len().checked_rem(self.element_size) != Some(0) {
+            return false;
+        }
+        let n = lease.data.len() / self.element_size;
+        !matches!(self.min_len, Some(min) if n < min as usize)
+            && !matches!(self.max_len, Some(max) if n > max as usize)
+    }
+}
+
+/// A lease built from fuzz input.
+#[derive(Debug)]
+struct FuzzLease {
+    read: bool,
+    write: bool,
+    data: Vec<u8>,
+}
+
+/// Reads fuzz input a byte at a time, producing zeros once it runs out, so
+/// that every input decodes to something.
+struct Input<'a>(&'a [u8]);
+
+impl Input<'_> {
+    fn byte(&mut self) -> u8 {
+        match self.0.split_first() {
+            Some((&b, rest)) => {
+                self.0 = rest;
+                b
+            }
+            None => 0,
+        }
+    }
+}
+
+/// Decodes `data` into a message for a server implementing the operations in
+/// `ops` (numbered from 1), sends it to a server task running `serve`, and
+/// panics if the response isn't right for the message.
+///
+/// `serve` should loop calling `dispatch` with a server whose operations all
+/// fail with `ClientError::WentAway`, as generated servers for fuzzing do.
+///
+/// Most inputs are steered towards messages that get past the dispatch checks,
+/// so that the fuzzer spends its time on argument decoding, but any operation
+/// number, message, reply buffer, and lease set can be produced.
+pub fn run(data: &[u8], ops: &[OpShape], serve: fn()) {
+    let mut input = Input(data);
+
+    // Operation: usually one of the interface's, sometimes anything.
+    let selector = input.byte();
+    let operation = if selector < 0xF0 && !ops.is_empty() {
+        (selector as usize % ops.len()) as u16 + 1
+    } else {
+        u16::from_le_bytes([input.byte(), input.byte()])
+    };
+    let shape = ops.get(usize::from(operation).wrapping_sub(1));
+
+    // Flags choosing whether each part of the message follows the shape.
+    let flags = input.byte();
+    let reply_capacity = match shape {
+        Some(s) if flags & 1 == 0 => s.reply_size,
+        _ => usize::from(input.byte()),
+    };
+
+    let mut leases = vec![];
+    match shape {
+        Some(s) if flags & 2 == 0 => {
+            for l in s.leases {
+                let len = if l.slice {
+                    usize::from(input.byte()) * l.element_size
+                } else {
+                    l.element_size
+                };
+                leases.push(FuzzLease {
+                    read: l.read,
+                    write: l.write,
+                    data: vec![0; len],
+                });
+            }
+        }
+        _ => {
+            for _ in 0..input.byte() % 4 {
+                // The simulation can't express a lease granting nothing, so
+                // every lease grants some access.
+                let access = input.byte() % 3 + 1;
+                leases.push(FuzzLease {
+                    read: access & 1 != 0,
+                    write: access & 2 != 0,
+                    data: vec![0; usize::from(input.byte())],
+                });
+            }
+        }
+    }
+
+    // The rest of the input is the message.
+    let mut message = input.0.to_vec();
+    if let Some(s) = shape {
+        if flags & 4 == 0 {
+            message.resize(s.message_size, 0);
+        }
+    }
+
+    let well_formed = shape.map(|s| {
+        message.len() == s.message_size
+            && reply_capacity >= s.reply_size
+            && leases.len() == s.leases.len()
+            && s.leases.iter().zip(&leases).all(|(s, l)| s.accepts(l))
+    });
+
+    let sim = Sim::new();
+    let server = sim.spawn(serve);
+    let (rc, _) = sim.run(|| {
+        let mut reply = vec![0; reply_capacity];
+        let leases = leases
+            .iter_mut()
+            .map(|l| match (l.read, l.write) {
+                (true, true) => Lease::read_write(&mut l.data),
+                (false, true) => Lease::write_only(&mut l.data),
+                _ => Lease::read_only(&l.data),
+            })
+            .collect::<Vec<_>>();
+        sim::sys_send(server, operation, &message, &mut reply, &leases)
+    });
+
+    assert!(
+        sim::extract_new_generation(rc).is_none(),
+        "server crashed on operation {} with message {:x?}",
+        operation,
+        message
+    );
+
+    let reached = rc == ClientError::WentAway as u32;
+    let rejected = rc == ClientError::UnknownOperation as u32
+        || rc == ClientError::BadMessage as u32
+        || rc == ClientError::BadLease as u32
+        || rc == ClientError::BadLeaseAttributes as u32
+        || rc == ClientError::BadLeaseLength as u32;
+    match well_formed {
+        // Reserved operations may be answered by `dispatch` itself.
+        None => assert!(
+            rejected || (operation >= FIRST_RESERVED_OP && rc == 0),
+            "operation {} produced response code {:#x}",
+            operation,
+            rc
+        ),
+        Some(true) => {
+            // Arguments converted with `FromPrimitive` may still be invalid.
+            let fallible = matches!(shape, Some(s) if s.fallible_args);
+            assert!(
+                reached || (fallible && rc == ClientError::BadMessage as u32),
+                "well-formed message for operation {} produced response \
+                 code {:#x}",
+                operation,
+                rc
+            );
+        }
+        Some(false) => assert!(
+            rejected,
+            "malformed message for operation {} produced response code {:#x}",
+            operation, rc
+        ),
+    }
+}
@@ -1,19 +1,61 @@
-#![no_std]
+#![cfg_attr(not(feature = "std"), no_std)]
 
 //! Runtime support types for code generated by the Idol compiler.
 //!
 //! Most uses of Idol don't need to pull this crate in, but generated servers
 //! often do.
+//!
+//! By default, this crate makes Hubris syscalls through `userlib`. With the
+//! `std` feature, it uses the simulation in `sim` instead, so that servers can
+//! be tested on a host.
+
+#[cfg(feature = "std")]
+pub mod fuzz;
+#[cfg(feature = "std")]
+pub mod sim;
+
+#[cfg(not(any(feature = "std", feature = "userlib")))]
+compile_error!(
+    "idol-runtime needs either the `userlib` feature, to run on Hubris, or \
+     the `std` feature, to run in simulation"
+);
+
+#[cfg(feature = "std")]
+use sim as sys;
+#[cfg(not(feature = "std"))]
+use userlib as sys;
 
 use core::marker::PhantomData;
 use core::num::NonZeroU32;
 use core::ops::Range;
-use userlib::{
+use sys::{
     sys_borrow_info, sys_borrow_read, sys_borrow_write, sys_recv, sys_reply,
     FromPrimitive, LeaseAttributes, RecvMessage, TaskId,
 };
 use zerocopy::{AsBytes, FromBytes};
 
+/// Operation numbers from here up are reserved for operations handled by the
+/// dispatch routines themselves, rather than by servers.
+pub const FIRST_RESERVED_OP: u16 = 0xFF00;
+
+/// Reserved operation that asks a server for the fingerprint of the interface
+/// it implements. Servers whose `ServerOp::HANDSHAKE` is set reply with the
+/// fingerprint as a little-endian `u64`; others reply with
+/// `ClientError::UnknownOperation`.
+pub const FINGERPRINT_OP: u16 = 0xFFFF;
+
+/// Reserved operation that asks a server for the definition of the interface
+/// it implements, for debug tooling. Servers whose `ServerOp::DEFINITION` is
+/// set answer it; others reply with `ClientError::UnknownOperation`.
+///
+/// The message is a little-endian `u32` offset into the definition, and the
+/// operation takes a single writable lease of bytes. The server copies as much
+/// of the definition from that offset as fits into the lease, and replies with
+/// two little-endian `u32`s: the total length of the definition, and the
+/// number of bytes copied. Callers with small buffers can fetch the whole
+/// definition in chunks by advancing the offset.
+pub const REFLECT_OP: u16 = 0xFFFE;
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 #[repr(u32)]
 pub enum ClientError {
@@ -21,6 +63,32 @@ pub enum ClientError {
     BadMessage = 0xFFFF_FE01,
     BadLease = 0xFFFF_FE02,
     WentAway = 0xFFFF_FE03,
+    /// The sender isn't allowed to call the operation, as declared with
+    /// `allowed_callers` or `deny_callers` in the interface.
+    AccessDenied = 0xFFFF_FE04,
+    /// A lease didn't grant the access the operation needs.
+    BadLeaseAttributes = 0xFFFF_FE05,
+    /// A lease was the wrong size for its type, or outside the lengths the
+    /// operation accepts.
+    BadLeaseLength = 0xFFFF_FE06,
+}
+
+impl ClientError {
+    /// Returns the `ClientError` that `code` represents, if any.
+    pub fn from_code(code: u32) -> Option<Self> {
+        [
+            Self::UnknownOperation,
+            Self::BadMessage,
+            Self::BadLease,
+            Self::WentAway,
+            Self::AccessDenied,
+            Self::BadLeaseAttributes,
+            Self::BadLeaseLength,
+        ]
+        .iter()
+        .copied()
+        .find(|e| u32::from(*e) == code)
+    }
 }
 
 impl From<ClientError> for u32 {
@@ -40,6 +108,55 @@ impl<E> RequestError<E> {
     pub fn went_away() -> Self {
         Self::Fail(ClientError::WentAway)
     }
+
+    /// Fails a request because of a problem with one of its leases. (This
+    /// can't be a `From` impl, as it would overlap `From<E>` when `E` is
+    /// `LeaseError`.)
+    pub fn lease(e: LeaseError) -> Self {
+        Self::Fail(e.into())
+    }
+}
+
+/// Ways that checking or accessing a lease can fail.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LeaseError {
+    /// The lender restarted, or never sent the lease at all.
+    LenderRestarted,
+    /// The lease doesn't grant the access needed.
+    WrongAttributes,
+    /// The lease is the wrong size for its type, or has fewer or more elements
+    /// than allowed.
+    BadLength,
+    /// A read or write moved fewer bytes than asked for, because the lease was
+    /// shorter than expected.
+    ShortTransfer,
+    /// A range wasn't within the lease, or didn't match the length of the
+    /// buffer it was copied to or from.
+    OutOfRange,
+    /// Bytes read from a `str` lease weren't UTF-8, or split a character.
+    InvalidUtf8,
+}
+
+/// Maps lease errors onto the codes sent to clients. A client that has
+/// restarted won't see its reply, so `WentAway` is as good as any code for
+/// those; short transfers also mean the lease changed under us.
+///
+/// `OutOfRange` maps to `BadMessage` rather than `BadLeaseLength`: the lease
+/// passed the length checks, and the range came from the message (or from the
+/// server), so it's the request that's at fault, not the lease.
+impl From<LeaseError> for ClientError {
+    fn from(e: LeaseError) -> Self {
+        match e {
+            LeaseError::LenderRestarted | LeaseError::ShortTransfer => {
+                Self::WentAway
+            }
+            LeaseError::WrongAttributes => Self::BadLeaseAttributes,
+            LeaseError::BadLength => Self::BadLeaseLength,
+            LeaseError::OutOfRange | LeaseError::InvalidUtf8 => {
+                Self::BadMessage
+            }
+        }
+    }
 }
 
 impl<E> From<E> for RequestError<E> {
@@ -90,6 +207,15 @@ pub trait ServerOp: FromPrimitive + Copy {
     fn max_reply_size(&self) -> usize;
     /// Number of leases required for this operation.
     fn required_lease_count(&self) -> usize;
+
+    /// Fingerprint of the interface, which the dispatch routines send in reply
+    /// to `FINGERPRINT_OP`. If this is `None`, the server doesn't take part in
+    /// the handshake, and `FINGERPRINT_OP` is treated as unknown.
+    const HANDSHAKE: Option<u64> = None;
+
+    /// Encoded interface definition, which the dispatch routines send in reply
+    /// to `REFLECT_OP`. If this is `None`, `REFLECT_OP` is treated as unknown.
+    const DEFINITION: Option<&'static [u8]> = None;
 }
 
 /// Trait implemented by things that serve.
@@ -119,6 +245,131 @@ pub trait Server<Op: ServerOp> {
     ) -> Result<(), u32>;
 }
 
+/// Trait for watching what the dispatch routines do, to count calls or time
+/// handlers without changing the server. Pass one to `dispatch_observed` or
+/// `dispatch_n_observed`; `()` ignores everything, and is what `dispatch` and
+/// `dispatch_n` use.
+///
+/// The hooks take `&self` so that an observer can be a `static`, where a
+/// debugger can find it. Observers that keep state should use `Cell` or
+/// `Counter`. Every hook does nothing by default.
+pub trait DispatchObserver<Op> {
+    /// Called for each message received, before it's checked, except for the
+    /// reserved handshake and reflection operations, which the dispatch
+    /// routines answer themselves. Handler latency is the time from this to
+    /// the matching `on_handled` or `on_reject`, one of which always follows.
+    fn on_recv(&self, _rm: &RecvMessage) {}
+
+    /// Called when a message is rejected with `err`, either by the dispatch
+    /// routine or by the server (e.g. because generated code couldn't decode
+    /// its arguments, or the server returned `ClientError::WentAway`).
+    fn on_reject(&self, _rm: &RecvMessage, _err: ClientError) {}
+
+    /// Called when the server has handled a call to `op`. `rc` is the response
+    /// code it returned, or 0 if it replied itself.
+    fn on_handled(&self, _op: Op, _rc: u32) {}
+
+    /// Called by `dispatch_n_observed` when notifications `bits` arrive,
+    /// before the server handles them.
+    fn on_notification(&self, _bits: u32) {}
+}
+
+impl<Op> DispatchObserver<Op> for () {}
+
+/// Event counter that can live in a `static`, for observers that a debugger
+/// reads from memory. It wraps an `AtomicU32`, so it has the same layout as a
+/// `u32`, and the count wraps on overflow.
+///
+/// Counting is a separate load and store, rather than an atomic add (which
+/// not every Hubris target has), so a counter must only be counted from one
+/// task, as it is when a server counts its own events.
+#[derive(Debug, Default)]
+#[repr(transparent)]
+pub struct Counter(core::sync::atomic::AtomicU32);
+
+impl Counter {
+    pub const fn new() -> Self {
+        Self(core::sync::atomic::AtomicU32::new(0))
+    }
+
+    /// Adds one to the count.
+    pub fn count(&self) {
+        use core::sync::atomic::Ordering::Relaxed;
+        self.0.store(self.0.load(Relaxed).wrapping_add(1), Relaxed);
+    }
+
+    pub fn get(&self) -> u32 {
+        self.0.load(core::sync::atomic::Ordering::Relaxed)
+    }
+}
+
+/// Counts of the calls to one operation that its server handled.
+#[derive(Debug, Default)]
+#[repr(C)]
+pub struct OpCounters {
+    pub calls: Counter,
+    /// Calls for which the server returned an error.
+    pub errors: Counter,
+}
+
+impl OpCounters {
+    pub const fn new() -> Self {
+        Self {
+            calls: Counter::new(),
+            errors: Counter::new(),
+        }
+    }
+
+    /// Counts a call whose response code was `rc`.
+    pub fn count(&self, rc: u32) {
+        self.calls.count();
+        if rc != 0 {
+            self.errors.count();
+        }
+    }
+}
+
+/// Counts of the messages a server rejected, by `ClientError`.
+#[derive(Debug, Default)]
+#[repr(C)]
+pub struct ClientErrorCounters {
+    pub unknown_operation: Counter,
+    pub bad_message: Counter,
+    pub bad_lease: Counter,
+    pub went_away: Counter,
+    pub access_denied: Counter,
+    pub bad_lease_attributes: Counter,
+    pub bad_lease_length: Counter,
+}
+
+impl ClientErrorCounters {
+    pub const fn new() -> Self {
+        Self {
+            unknown_operation: Counter::new(),
+            bad_message: Counter::new(),
+            bad_lease: Counter::new(),
+            went_away: Counter::new(),
+            access_denied: Counter::new(),
+            bad_lease_attributes: Counter::new(),
+            bad_lease_length: Counter::new(),
+        }
+    }
+
+    pub fn count(&self, err: ClientError) {
+        match err {
+            ClientError::UnknownOperation => self.unknown_operation.count(),
+            ClientError::BadMessage => self.bad_message.count(),
+            ClientError::BadLease => self.bad_lease.count(),
+            ClientError::WentAway => self.went_away.count(),
+            ClientError::AccessDenied => self.access_denied.count(),
+            ClientError::BadLeaseAttributes => {
+                self.bad_lease_attributes.count()
+            }
+            ClientError::BadLeaseLength => self.bad_lease_length.count(),
+        }
+    }
+}
+
 /// Generic server dispatch routine for cases where notifications are not
 /// required.
 ///
@@ -133,51 +384,26 @@ pub trait Server<Op: ServerOp> {
 /// generate that impl for you, based on your impl of an interface-specific
 /// generated trait.
 ///
-/// If you need notifications, use `dispatch_n`.
+/// If you need notifications, use `dispatch_n`. To watch what happens, use
+/// `dispatch_observed`.
 pub fn dispatch<S, Op: ServerOp>(buffer: &mut [u8], server: &mut S)
 where
     for<'a> (core::marker::PhantomData<Op>, &'a mut S): Server<Op>,
 {
-    let mut server = (core::marker::PhantomData, server);
-    let rm = match sys_recv(buffer, 0, server.recv_source()) {
-        Ok(rm) => rm,
-        Err(_) => {
-            server.closed_recv_fail();
-            return;
-        }
-    };
-
-    let op = match Op::from_u32(rm.operation) {
-        Some(op) => op,
-        None => {
-            sys_reply(rm.sender, ClientError::UnknownOperation as u32, &[]);
-            return;
-        }
-    };
-
-    let incoming_truncated = rm.message_len > buffer.len();
-    let reply_would_truncate = rm.response_capacity < op.max_reply_size();
-    if incoming_truncated || reply_would_truncate {
-        sys_reply(rm.sender, ClientError::BadMessage as u32, &[]);
-        return;
-    }
-
-    let incoming = &buffer[..rm.message_len];
-
-    if rm.lease_count != op.required_lease_count() {
-        sys_reply(rm.sender, ClientError::BadLease as u32, &[]);
-        return;
-    }
+    dispatch_observed(buffer, server, &());
+}
 
-    match server.handle(op, incoming, &rm) {
-        Ok(()) => {
-            // stub has taken care of it.
-        }
-        Err(code) => {
-            // stub has used the convenience return for data-less errors,
-            // we'll do the reply.
-            sys_reply(rm.sender, code, &[]);
-        }
+/// Version of `dispatch` that reports what it does to `observer`.
+pub fn dispatch_observed<S, Op: ServerOp>(
+    buffer: &mut [u8],
+    server: &mut S,
+    observer: &impl DispatchObserver<Op>,
+) where
+    for<'a> (core::marker::PhantomData<Op>, &'a mut S): Server<Op>,
+{
+    let mut server = (core::marker::PhantomData, server);
+    if let Some(rm) = recv_message(buffer, 0, &mut server) {
+        dispatch_message(buffer, &mut server, &rm, observer);
     }
 }
 
@@ -197,60 +423,196 @@ where
 /// `server` is required to directly impl `NotificationHandler` (i.e. you must
 /// write the impl yourself).
 ///
-/// If you don't need notifications, use `dispatch`.
+/// If you don't need notifications, use `dispatch`. To watch what happens, use
+/// `dispatch_n_observed`.
 pub fn dispatch_n<S: NotificationHandler, Op: ServerOp>(
     buffer: &mut [u8],
     server: &mut S,
 ) where
     for<'a> (core::marker::PhantomData<Op>, &'a mut S): Server<Op>,
+{
+    dispatch_n_observed(buffer, server, &());
+}
+
+/// Version of `dispatch_n` that reports what it does to `observer`.
+pub fn dispatch_n_observed<S: NotificationHandler, Op: ServerOp>(
+    buffer: &mut [u8],
+    server: &mut S,
+    observer: &impl DispatchObserver<Op>,
+) where
+    for<'a> (core::marker::PhantomData<Op>, &'a mut S): Server<Op>,
 {
     let mut server = (core::marker::PhantomData, server);
     let mask = server.1.current_notification_mask();
-    let rm = match sys_recv(buffer, mask, server.recv_source()) {
-        Ok(rm) => rm,
-        Err(_) => {
-            server.closed_recv_fail();
-            return;
-        }
+    let rm = match recv_message(buffer, mask, &mut server) {
+        Some(rm) => rm,
+        None => return,
     };
 
     if rm.sender == TaskId::KERNEL {
+        observer.on_notification(rm.operation);
         server.1.handle_notification(rm.operation);
         return;
     }
 
-    let incoming = &buffer[..rm.message_len];
+    dispatch_message(buffer, &mut server, &rm, observer);
+}
 
-    let op = match Op::from_u32(rm.operation) {
-        Some(op) => op,
-        None => {
-            sys_reply(rm.sender, ClientError::UnknownOperation as u32, &[]);
-            return;
+/// Receives the next message or notification into `buffer`, accepting
+/// notifications in `mask`. If the server asked for a closed receive from a
+/// task that has since died, this tells the server and returns `None`.
+fn recv_message<Op: ServerOp, S: Server<Op>>(
+    buffer: &mut [u8],
+    mask: u32,
+    server: &mut S,
+) -> Option<RecvMessage> {
+    match sys_recv(buffer, mask, server.recv_source()) {
+        Ok(rm) => Some(rm),
+        Err(_) => {
+            server.closed_recv_fail();
+            None
         }
-    };
-
-    if rm.response_capacity < op.max_reply_size() {
-        sys_reply(rm.sender, ClientError::BadMessage as u32, &[]);
-        return;
     }
+}
 
-    if rm.lease_count != op.required_lease_count() {
-        sys_reply(rm.sender, ClientError::BadLease as u32, &[]);
+/// Handles a message that has been received into `buffer`, replying to it
+/// unless the server takes responsibility for that, and reporting what happens
+/// to `observer`.
+///
+/// This is the part of dispatch shared by `dispatch`, `dispatch_n`, and any
+/// custom dispatch loop: it answers reserved operations, checks the message
+/// with `check_message`, and passes it to the server. Loops that receive
+/// messages some other way should call this, so that every server validates
+/// messages the same way.
+pub fn dispatch_message<Op: ServerOp, S: Server<Op>>(
+    buffer: &[u8],
+    server: &mut S,
+    rm: &RecvMessage,
+    observer: &impl DispatchObserver<Op>,
+) {
+    // Reserved operations have no `Op` to report them with, so they're
+    // answered before the observer hears about the message.
+    if handle_reserved::<Op>(rm, buffer) {
         return;
     }
+    observer.on_recv(rm);
 
-    match server.handle(op, incoming, &rm) {
+    let (op, incoming) = match check_message::<Op>(rm, buffer) {
+        Ok(checked) => checked,
+        Err(e) => {
+            observer.on_reject(rm, e);
+            sys_reply(rm.sender, u32::from(e), &[]);
+            return;
+        }
+    };
+    match server.handle(op, incoming, rm) {
         Ok(()) => {
             // stub has taken care of it.
+            observer.on_handled(op, 0);
         }
         Err(code) => {
             // stub has used the convenience return for data-less errors,
             // we'll do the reply.
+            match ClientError::from_code(code) {
+                Some(e) => observer.on_reject(rm, e),
+                None => observer.on_handled(op, code),
+            }
             sys_reply(rm.sender, code, &[]);
         }
     }
 }
 
+/// Checks that `rm`, received into `buffer`, is a message the server can
+/// handle: that its operation is known, that it wasn't truncated, that the
+/// sender can accept the largest reply, and that it came with the right
+/// number of leases. Returns the operation and the message contents.
+pub fn check_message<'b, Op: ServerOp>(
+    rm: &RecvMessage,
+    buffer: &'b [u8],
+) -> Result<(Op, &'b [u8]), ClientError> {
+    let op = Op::from_u32(rm.operation).ok_or(ClientError::UnknownOperation)?;
+
+    let incoming = buffer
+        .get(..rm.message_len)
+        .ok_or(ClientError::BadMessage)?;
+    if rm.response_capacity < op.max_reply_size() {
+        return Err(ClientError::BadMessage);
+    }
+
+    if rm.lease_count != op.required_lease_count() {
+        return Err(ClientError::BadLease);
+    }
+
+    Ok((op, incoming))
+}
+
+/// Answers the reserved operations that the dispatch routines handle on the
+/// server's behalf. Returns `true` if `rm` was one of them, and has been
+/// replied to.
+fn handle_reserved<Op: ServerOp>(rm: &RecvMessage, buffer: &[u8]) -> bool {
+    let result = if rm.operation == u32::from(FINGERPRINT_OP) {
+        match Op::HANDSHAKE {
+            Some(fingerprint) => reply_fingerprint(rm, fingerprint),
+            None => return false,
+        }
+    } else if rm.operation == u32::from(REFLECT_OP) {
+        match Op::DEFINITION {
+            Some(definition) => reply_definition(rm, buffer, definition),
+            None => return false,
+        }
+    } else {
+        return false;
+    };
+
+    if let Err(e) = result {
+        sys_reply(rm.sender, e as u32, &[]);
+    }
+    true
+}
+
+/// Implementation of `FINGERPRINT_OP`.
+fn reply_fingerprint(
+    rm: &RecvMessage,
+    fingerprint: u64,
+) -> Result<(), ClientError> {
+    let reply = fingerprint.to_le_bytes();
+    if rm.response_capacity < reply.len() {
+        return Err(ClientError::BadMessage);
+    }
+    sys_reply(rm.sender, 0, &reply);
+    Ok(())
+}
+
+/// Implementation of `REFLECT_OP`.
+fn reply_definition(
+    rm: &RecvMessage,
+    buffer: &[u8],
+    definition: &[u8],
+) -> Result<(), ClientError> {
+    let mut reply = [0; 8];
+    if rm.response_capacity < reply.len() {
+        return Err(ClientError::BadMessage);
+    }
+    let offset = buffer
+        .get(..rm.message_len)
+        .and_then(|msg| <[u8; 4]>::try_from(msg).ok())
+        .map(|bytes| u32::from_le_bytes(bytes) as usize)
+        .ok_or(ClientError::BadMessage)?;
+    let remaining = definition.get(offset..).ok_or(ClientError::BadMessage)?;
+
+    if rm.lease_count != 1 {
+        return Err(ClientError::BadLease);
+    }
+    let sink = Leased::<W, [u8]>::write_only_slice(rm.sender, 0, None, None)?;
+    let chunk = &remaining[..remaining.len().min(sink.len())];
+    sink.write_range(0..chunk.len(), chunk)?;
+
+    reply[..4].copy_from_slice(&(definition.len() as u32).to_le_bytes());
+    reply[4..].copy_from_slice(&(chunk.len() as u32).to_le_bytes());
+    sys_reply(rm.sender, 0, &reply);
+    Ok(())
+}
+
 /// Marker trait implemented by types that can serve as lease attribute
 /// indicators.
 ///
@@ -308,7 +670,8 @@ impl AttributeWrite for RW {}
 /// 2. The attributes used on the lease by the client match `A`.
 /// 3. The size of the leased data is correct for `T` -- either it contains
 ///    exactly one `T`, or, if `T` is a slice `[E]` for some type `E`, it
-///    contains an integral number of values of type `E`.
+///    contains an integral number of values of type `E`. (A `str` lease can be
+///    any number of bytes; they're checked to be UTF-8 as they're read.)
 ///
 /// If any of these checks fail, the dispatch code will return an error to the
 /// client before calling into the server impl.
@@ -346,15 +709,16 @@ impl<A: Attribute, T> Leased<A, T> {
         lender: TaskId,
         index: usize,
         required_atts: LeaseAttributes,
-    ) -> Option<()> {
-        let info = sys_borrow_info(lender, index)?;
+    ) -> Result<(), LeaseError> {
+        let info = sys_borrow_info(lender, index)
+            .ok_or(LeaseError::LenderRestarted)?;
         if !info.attributes.contains(required_atts) {
-            return None;
+            return Err(LeaseError::WrongAttributes);
         }
         if info.len != core::mem::size_of::<T>() {
-            return None;
+            return Err(LeaseError::BadLength);
         }
-        Some(())
+        Ok(())
     }
 }
 
@@ -371,29 +735,55 @@ impl<A: Attribute, T> Leased<A, [T]> {
         self.len == 0
     }
 
+    /// Internal implementation factor for the range operations: checks that
+    /// `range` is within the lease and has `buf_len` elements, and returns its
+    /// offset and length in bytes.
+    fn range_bytes(
+        &self,
+        range: &Range<usize>,
+        buf_len: usize,
+    ) -> Result<(usize, usize), LeaseError> {
+        if range.start > range.end
+            || range.end > self.len
+            || range.end - range.start != buf_len
+        {
+            return Err(LeaseError::OutOfRange);
+        }
+        // These can't overflow, as the lease was checked to fit in memory.
+        let size = core::mem::size_of::<T>();
+        Ok((size * range.start, size * buf_len))
+    }
+
     /// Internal implementation factor for checking slices.
     fn check_slice(
         lender: TaskId,
         index: usize,
         required_atts: LeaseAttributes,
+        min_len: Option<NonZeroU32>,
         max_len: Option<NonZeroU32>,
-    ) -> Option<usize> {
-        let info = sys_borrow_info(lender, index)?;
+    ) -> Result<usize, LeaseError> {
+        let info = sys_borrow_info(lender, index)
+            .ok_or(LeaseError::LenderRestarted)?;
         if !info.attributes.contains(required_atts) {
-            return None;
+            return Err(LeaseError::WrongAttributes);
         }
         if info.len % core::mem::size_of::<T>() != 0 {
-            return None;
+            return Err(LeaseError::BadLength);
         }
         let len = info.len / core::mem::size_of::<T>();
 
+        if let Some(min_len) = min_len {
+            if len < min_len.get() as usize {
+                return Err(LeaseError::BadLength);
+            }
+        }
         if let Some(max_len) = max_len {
             if len > max_len.get() as usize {
-                return None;
+                return Err(LeaseError::BadLength);
             }
         }
 
-        Some(len)
+        Ok(len)
     }
 }
 
@@ -404,10 +794,10 @@ impl<T> Leased<R, T> {
     /// called from the generated server stub code.
     ///
     /// This operation will perform `sys_borrow_info` to check the properties
-    /// described in the docs for `Leased`. If any fail, it returns `None`.
-    pub fn read_only(lender: TaskId, index: usize) -> Option<Self> {
+    /// described in the docs for `Leased`, returning the first that fails.
+    pub fn read_only(lender: TaskId, index: usize) -> Result<Self, LeaseError> {
         Self::check_sized(lender, index, LeaseAttributes::READ)?;
-        Some(Self {
+        Ok(Self {
             lender,
             index,
             len: 1,
@@ -422,15 +812,23 @@ impl<T> Leased<R, [T]> {
     /// be called from the generated server stub code.
     ///
     /// This operation will perform `sys_borrow_info` to check the properties
-    /// described in the docs for `Leased`. If any fail, it returns `None`.
+    /// described in the docs for `Leased`, and that the slice has between
+    /// `min_len` and `max_len` elements, if given, returning the first that
+    /// fails.
     pub fn read_only_slice(
         lender: TaskId,
         index: usize,
+        min_len: Option<NonZeroU32>,
         max_len: Option<NonZeroU32>,
-    ) -> Option<Self> {
-        let len =
-            Self::check_slice(lender, index, LeaseAttributes::READ, max_len)?;
-        Some(Self {
+    ) -> Result<Self, LeaseError> {
+        let len = Self::check_slice(
+            lender,
+            index,
+            LeaseAttributes::READ,
+            min_len,
+            max_len,
+        )?;
+        Ok(Self {
             lender,
             index,
             len,
@@ -446,10 +844,13 @@ impl<T> Leased<W, T> {
     /// be called from the generated server stub code.
     ///
     /// This operation will perform `sys_borrow_info` to check the properties
-    /// described in the docs for `Leased`. If any fail, it returns `None`.
-    pub fn write_only(lender: TaskId, index: usize) -> Option<Self> {
+    /// described in the docs for `Leased`, returning the first that fails.
+    pub fn write_only(
+        lender: TaskId,
+        index: usize,
+    ) -> Result<Self, LeaseError> {
         Self::check_sized(lender, index, LeaseAttributes::WRITE)?;
-        Some(Self {
+        Ok(Self {
             lender,
             index,
             len: 1,
@@ -464,15 +865,54 @@ impl<T> Leased<W, [T]> {
     /// to be called from the generated server stub code.
     ///
     /// This operation will perform `sys_borrow_info` to check the properties
-    /// described in the docs for `Leased`. If any fail, it returns `None`.
+    /// described in the docs for `Leased`, and that the slice has between
+    /// `min_len` and `max_len` elements, if given, returning the first that
+    /// fails.
     pub fn write_only_slice(
         lender: TaskId,
         index: usize,
+        min_len: Option<NonZeroU32>,
+        max_len: Option<NonZeroU32>,
+    ) -> Result<Self, LeaseError> {
+        let len = Self::check_slice(
+            lender,
+            index,
+            LeaseAttributes::WRITE,
+            min_len,
+            max_len,
+        )?;
+        Ok(Self {
+            lender,
+            index,
+            len,
+            _marker: PhantomData,
+        })
+    }
+}
+
+/// These functions are only available for read-only leased strings.
+impl Leased<R, str> {
+    /// Creates a new `Leased` describing a read-only string. This is intended
+    /// to be called from the generated server stub code.
+    ///
+    /// This operation will perform `sys_borrow_info` to check the properties
+    /// described in the docs for `Leased`, and that the string has between
+    /// `min_len` and `max_len` bytes, if given, returning the first that
+    /// fails. It doesn't check the contents.
+    pub fn read_only_str(
+        lender: TaskId,
+        index: usize,
+        min_len: Option<NonZeroU32>,
         max_len: Option<NonZeroU32>,
-    ) -> Option<Self> {
-        let len =
-            Self::check_slice(lender, index, LeaseAttributes::WRITE, max_len)?;
-        Some(Self {
+    ) -> Result<Self, LeaseError> {
+        let len = Leased::<R, [u8]>::check_slice(
+            lender,
+            index,
+            LeaseAttributes::READ,
+            min_len,
+            max_len,
+        )?;
+        Ok(Self {
             lender,
             index,
             len,
@@ -481,6 +921,51 @@ impl<T> Leased<W, [T]> {
     }
 }
 
+/// These functions are available on any readable leased string.
+impl<A: AttributeRead> Leased<A, str> {
+    /// Returns the length of the leased string in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Checks whether the leased string is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Reads the whole leased string into the start of `buf`, and returns it.
+    ///
+    /// Returns `Err(LeaseError::OutOfRange)` if `buf` is too short, and
+    /// `Err(LeaseError::InvalidUtf8)` if the lease doesn't hold UTF-8.
+    pub fn read_str<'b>(
+        &self,
+        buf: &'b mut [u8],
+    ) -> Result<&'b str, LeaseError> {
+        self.read_str_range(0..self.len, buf)
+    }
+
+    /// Reads the bytes in `range` of the leased string into the start of
+    /// `buf`, and returns them.
+    ///
+    /// Returns `Err(LeaseError::OutOfRange)` if `range` is outside the lease or
+    /// `buf` is too short for it, and `Err(LeaseError::InvalidUtf8)` if the
+    /// bytes aren't UTF-8, including if `range` splits a character.
+    pub fn read_str_range<'b>(
+        &self,
+        range: Range<usize>,
+        buf: &'b mut [u8],
+    ) -> Result<&'b str, LeaseError> {
+        if range.start > range.end || range.end > self.len {
+            return Err(LeaseError::OutOfRange);
+        }
+        let buf = buf
+            .get_mut(..range.end - range.start)
+            .ok_or(LeaseError::OutOfRange)?;
+        read_bytes(self, range.start, buf)?;
+        core::str::from_utf8(buf).map_err(|_| LeaseError::InvalidUtf8)
+    }
+}
+
 /// These functions are available on any readable lease (that is, read-only or
 /// read-write) where the content type `T` is `Sized` and can be moved around by
 /// naive mem-copy.
@@ -488,18 +973,15 @@ impl<A: AttributeRead, T: Sized + Copy + FromBytes + AsBytes> Leased<A, T> {
     /// Reads the leased value by copy.
     ///
     /// If the lending task has been restarted between the time we checked lease
-    /// attributes and the time you call `read`, this will return `None`.
-    /// Otherwise, it returns `Some(value)`. It's therefore safe to treat a
-    /// `None` return as aborting the request.
-    pub fn read(&self) -> Option<T> {
+    /// attributes and the time you call `read`, this will return
+    /// `Err(LeaseError::LenderRestarted)`. It's safe to treat any error as
+    /// aborting the request.
+    pub fn read(&self) -> Result<T, LeaseError> {
         let mut temp = T::new_zeroed();
         let (rc, len) =
             sys_borrow_read(self.lender, self.index, 0, temp.as_bytes_mut());
-        if rc != 0 || len != core::mem::size_of::<T>() {
-            None
-        } else {
-            Some(temp)
-        }
+        transfer_result(rc, len, core::mem::size_of::<T>())?;
+        Ok(temp)
     }
 }
 
@@ -513,61 +995,64 @@ impl<A: AttributeRead, T: Sized + Copy + FromBytes + AsBytes> Leased<A, [T]> {
     /// this will panic.
     ///
     /// If the lending task has been restarted between the time we checked lease
-    /// attributes and the time you call `read_at`, this will return `None`.
-    /// Otherwise, it returns `Some(value)`. It's therefore safe to treat a
-    /// `None` return as aborting the request.
-    pub fn read_at(&self, index: usize) -> Option<T> {
+    /// attributes and the time you call `read_at`, this will return
+    /// `Err(LeaseError::LenderRestarted)`. It's safe to treat any error as
+    /// aborting the request.
+    pub fn read_at(&self, index: usize) -> Result<T, LeaseError> {
         assert!(index < self.len);
 
         let mut temp = T::new_zeroed();
-        let offset = core::mem::size_of::<T>().checked_mul(index)?;
+        let offset = core::mem::size_of::<T>()
+            .checked_mul(index)
+            .ok_or(LeaseError::BadLength)?;
         let (rc, len) = sys_borrow_read(
             self.lender,
             self.index,
             offset,
             temp.as_bytes_mut(),
         );
-        if rc != 0 || len != core::mem::size_of::<T>() {
-            None
-        } else {
-            Some(temp)
-        }
+        transfer_result(rc, len, core::mem::size_of::<T>())?;
+        Ok(temp)
     }
 
     /// Reads a range of elements of the leased slice into `dest` by copy.
     ///
-    /// Like indexing a native slice, `range.start` must be less than
-    /// `self.len()`, and `range.end` must be less than or equal to
-    /// `self.len()`, or this will panic.
+    /// Like indexing a native slice, `range` must be within `0..self.len()`,
+    /// and like `copy_from_slice`, `dest` must be the same length as `range`,
+    /// or this will panic. Use `try_read_range` if the range comes from a
+    /// client.
     ///
     /// If the lending task has been restarted between the time we checked lease
-    /// attributes and the time you call `read_range`, this will return `None`.
-    /// Otherwise, it returns `Some(value)`. It's therefore safe to treat a
-    /// `None` return as aborting the request.
+    /// attributes and the time you call `read_range`, this will return
+    /// `Err(LeaseError::LenderRestarted)`. It's safe to treat any error as
+    /// aborting the request.
     pub fn read_range(
         &self,
         range: Range<usize>,
         dest: &mut [T],
-    ) -> Result<(), ()> {
-        let offset = core::mem::size_of::<T>()
-            .checked_mul(range.start)
-            .ok_or(())?;
-        let expected_len = core::mem::size_of::<T>()
-            .checked_mul(range.end - range.start)
-            .ok_or(())?;
+    ) -> Result<(), LeaseError> {
+        assert!(range.start <= range.end && range.end <= self.len);
+        assert_eq!(dest.len(), range.end - range.start);
+        self.try_read_range(range, dest)
+    }
 
+    /// Reads a range of elements of the leased slice into `dest` by copy, like
+    /// `read_range`, but returns `Err(LeaseError::OutOfRange)` instead of
+    /// panicking if `range` is outside the lease or isn't the same length as
+    /// `dest`.
+    pub fn try_read_range(
+        &self,
+        range: Range<usize>,
+        dest: &mut [T],
+    ) -> Result<(), LeaseError> {
+        let (offset, expected_len) = self.range_bytes(&range, dest.len())?;
         let (rc, len) = sys_borrow_read(
             self.lender,
             self.index,
             offset,
             dest.as_bytes_mut(),
         );
-
-        if rc != 0 || len != expected_len {
-            Err(())
-        } else {
-            Ok(())
-        }
+        transfer_result(rc, len, expected_len)
     }
 }
 
@@ -578,17 +1063,13 @@ impl<A: AttributeWrite, T: Sized + Copy + AsBytes> Leased<A, T> {
     /// Writes the leased value by copy.
     ///
     /// If the lending task has been restarted between the time we checked lease
-    /// attributes and the time you call `write`, this will return `Err(())`.
-    /// Otherwise, it returns `Ok(())`. It's therefore safe to treat an `Err`
-    /// return as aborting the request.
-    pub fn write(&self, value: T) -> Result<(), ()> {
+    /// attributes and the time you call `write`, this will return
+    /// `Err(LeaseError::LenderRestarted)`. It's safe to treat any error as
+    /// aborting the request.
+    pub fn write(&self, value: T) -> Result<(), LeaseError> {
         let (rc, len) =
             sys_borrow_write(self.lender, self.index, 0, value.as_bytes());
-        if rc != 0 || len != core::mem::size_of::<T>() {
-            Err(())
-        } else {
-            Ok(())
-        }
+        transfer_result(rc, len, core::mem::size_of::<T>())
     }
 }
 
@@ -602,53 +1083,353 @@ impl<A: AttributeWrite, T: Sized + Copy + AsBytes> Leased<A, [T]> {
     /// this will panic.
     ///
     /// If the lending task has been restarted between the time we checked lease
-    /// attributes and the time you call `write_at`, this will return `Err(())`.
-    /// Otherwise, it returns `Ok(())`. It's therefore safe to treat an `Err`
-    /// return as aborting the request.
-    pub fn write_at(&self, index: usize, value: T) -> Result<(), ()> {
-        let offset = core::mem::size_of::<T>().checked_mul(index).ok_or(())?;
+    /// attributes and the time you call `write_at`, this will return
+    /// `Err(LeaseError::LenderRestarted)`. It's safe to treat any error as
+    /// aborting the request.
+    pub fn write_at(&self, index: usize, value: T) -> Result<(), LeaseError> {
+        assert!(index < self.len);
+
+        let offset = core::mem::size_of::<T>()
+            .checked_mul(index)
+            .ok_or(LeaseError::BadLength)?;
         let (rc, len) =
             sys_borrow_write(self.lender, self.index, offset, value.as_bytes());
-        if rc != 0 || len != core::mem::size_of::<T>() {
-            Err(())
-        } else {
-            Ok(())
-        }
+        transfer_result(rc, len, core::mem::size_of::<T>())
     }
 
     /// Writes a range of elements from `src` into the leased slice by copy.
     ///
-    /// Like indexing a native slice, `range.start` must be less than
-    /// `self.len()`, and `range.end` must be less than or equal to
-    /// `self.len()`, or this will panic.
+    /// Like indexing a native slice, `range` must be within `0..self.len()`,
+    /// and like `copy_from_slice`, `src` must be the same length as `range`,
+    /// or this will panic. Use `try_write_range` if the range comes from a
+    /// client.
     ///
     /// If the lending task has been restarted between the time we checked lease
     /// attributes and the time you call `write_range`, this will return
-    /// `Err(())`. Otherwise, it returns `Ok(())`. It's therefore safe to treat
-    /// an `Err` return as aborting the request.
+    /// `Err(LeaseError::LenderRestarted)`. It's safe to treat any error as
+    /// aborting the request.
     pub fn write_range(
         &self,
         range: Range<usize>,
         src: &[T],
-    ) -> Result<(), ()> {
-        let offset = core::mem::size_of::<T>()
-            .checked_mul(range.start)
-            .ok_or(())?;
-        let expected_len = core::mem::size_of::<T>()
-            .checked_mul(range.end - range.start)
-            .ok_or(())?;
+    ) -> Result<(), LeaseError> {
+        assert!(range.start <= range.end && range.end <= self.len);
+        assert_eq!(src.len(), range.end - range.start);
+        self.try_write_range(range, src)
+    }
 
+    /// Writes a range of elements from `src` into the leased slice by copy,
+    /// like `write_range`, but returns `Err(LeaseError::OutOfRange)` instead
+    /// of panicking if `range` is outside the lease or isn't the same length
+    /// as `src`.
+    pub fn try_write_range(
+        &self,
+        range: Range<usize>,
+        src: &[T],
+    ) -> Result<(), LeaseError> {
+        let (offset, expected_len) = self.range_bytes(&range, src.len())?;
         let (rc, len) =
             sys_borrow_write(self.lender, self.index, offset, src.as_bytes());
+        transfer_result(rc, len, expected_len)
+    }
+}
 
-        if rc != 0 || len != expected_len {
-            Err(())
-        } else {
-            Ok(())
+/// Interprets the result of a `sys_borrow_read` or `sys_borrow_write` that
+/// was expected to move `expected` bytes.
+fn transfer_result(
+    rc: u32,
+    len: usize,
+    expected: usize,
+) -> Result<(), LeaseError> {
+    if rc != 0 {
+        Err(LeaseError::LenderRestarted)
+    } else if len != expected {
+        Err(LeaseError::ShortTransfer)
+    } else {
+        Ok(())
+    }
+}
+
+/// These functions are available on any readable leased slice, for streaming
+/// its contents through a buffer.
+impl<A: AttributeRead, T: Sized + Copy + FromBytes + AsBytes> Leased<A, [T]> {
+    /// Reads the leased slice in chunks of up to `buf.len()` elements, using
+    /// `buf` as scratch space. See `LeaseChunks`.
+    ///
+    /// This will panic if `buf` is empty.
+    pub fn chunks<'a>(&'a self, buf: &'a mut [T]) -> LeaseChunks<'a, A, T> {
+        assert!(!buf.is_empty());
+        LeaseChunks {
+            lease: self,
+            buf,
+            offset: 0,
+            failed: false,
+        }
+    }
+}
+
+/// These functions are available on any writable leased slice, for streaming
+/// data into it through a buffer.
+impl<A: AttributeWrite, T: Sized + Copy + AsBytes> Leased<A, [T]> {
+    /// Writes to the leased slice from the start, collecting up to `buf.len()`
+    /// elements in `buf` before copying them to the lender. See `LeaseWriter`.
+    ///
+    /// This will panic if `buf` is empty.
+    pub fn writer<'a>(&'a self, buf: &'a mut [T]) -> LeaseWriter<'a, A, T> {
+        assert!(!buf.is_empty());
+        LeaseWriter {
+            lease: self,
+            buf,
+            filled: 0,
+            offset: 0,
+            failed: None,
+        }
+    }
+}
+
+/// Reads a leased slice in order, one buffer-full at a time. Created by
+/// `Leased::chunks`.
+///
+/// Each chunk borrows the buffer, so this can't be an `Iterator`; use it with
+/// `while let Some(chunk) = chunks.next_chunk()` instead.
+pub struct LeaseChunks<'a, A: Attribute, T> {
+    lease: &'a Leased<A, [T]>,
+    buf: &'a mut [T],
+    offset: usize,
+    failed: bool,
+}
+
+impl<'a, A: AttributeRead, T: Sized + Copy + FromBytes + AsBytes>
+    LeaseChunks<'a, A, T>
+{
+    /// Reads the next chunk, returning the index of its first element in the
+    /// lease and its contents. Returns `None` once the whole lease has been
+    /// read, or after returning an error (such as the lender restarting).
+    pub fn next_chunk(&mut self) -> Option<Result<(usize, &[T]), LeaseError>> {
+        let remaining = self.lease.len() - self.offset;
+        if self.failed || remaining == 0 {
+            return None;
+        }
+        let start = self.offset;
+        let n = remaining.min(self.buf.len());
+        if let Err(e) =
+            self.lease.read_range(start..start + n, &mut self.buf[..n])
+        {
+            self.failed = true;
+            return Some(Err(e));
+        }
+        self.offset += n;
+        Some(Ok((start, &self.buf[..n])))
+    }
+
+    /// Returns the index in the lease of the next element to be read.
+    pub fn position(&self) -> usize {
+        self.offset
+    }
+}
+
+/// Writes to a leased slice in order, copying to the lender a buffer-full at a
+/// time. Created by `Leased::writer`.
+///
+/// Anything still buffered is lost when this is dropped, so call `flush` when
+/// done.
+pub struct LeaseWriter<'a, A: Attribute, T> {
+    lease: &'a Leased<A, [T]>,
+    buf: &'a mut [T],
+    filled: usize,
+    offset: usize,
+    failed: Option<LeaseError>,
+}
+
+impl<'a, A: AttributeWrite, T: Sized + Copy + AsBytes> LeaseWriter<'a, A, T> {
+    /// Appends as much of `src` as fits in the rest of the lease, returning
+    /// the number of elements taken, which is less than `src.len()` only once
+    /// the lease is full.
+    ///
+    /// Once copying to the lender fails, this and `flush` keep returning the
+    /// same error.
+    pub fn write(&mut self, mut src: &[T]) -> Result<usize, LeaseError> {
+        if let Some(e) = self.failed {
+            return Err(e);
+        }
+        let room = self.remaining();
+        src = &src[..src.len().min(room)];
+        let taken = src.len();
+        while !src.is_empty() {
+            if self.filled == self.buf.len() {
+                self.flush()?;
+            }
+            let n = src.len().min(self.buf.len() - self.filled);
+            self.buf[self.filled..self.filled + n].copy_from_slice(&src[..n]);
+            self.filled += n;
+            src = &src[n..];
+        }
+        Ok(taken)
+    }
+
+    /// Copies anything buffered to the lender.
+    pub fn flush(&mut self) -> Result<(), LeaseError> {
+        if let Some(e) = self.failed {
+            return Err(e);
+        }
+        let range = self.offset..self.offset + self.filled;
+        if let Err(e) = self.lease.write_range(range, &self.buf[..self.filled])
+        {
+            self.failed = Some(e);
+            return Err(e);
+        }
+        self.offset += self.filled;
+        self.filled = 0;
+        Ok(())
+    }
+
+    /// Returns the number of elements written so far, including any still
+    /// buffered.
+    pub fn position(&self) -> usize {
+        self.offset + self.filled
+    }
+
+    /// Returns the number of elements that can still be written before the
+    /// lease is full.
+    pub fn remaining(&self) -> usize {
+        self.lease.len() - self.position()
+    }
+}
+
+/// Size in bytes of the stack buffer used by the lease copy functions. Copies
+/// bigger than this take several round trips through the kernel.
+pub const COPY_BUFFER_SIZE: usize = 64;
+
+/// Which side of a copy failed, and why. Returned by `copy_lease`,
+/// `copy_from_lease`, and `copy_to_lease`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CopyError<S = LeaseError, D = LeaseError> {
+    /// Reading from the source failed.
+    Source(S),
+    /// Writing to the destination failed.
+    Dest(D),
+}
+
+/// A failed copy between two leases is reported to the client as whichever
+/// lease failed would be on its own.
+impl From<CopyError> for ClientError {
+    fn from(e: CopyError) -> Self {
+        match e {
+            CopyError::Source(e) | CopyError::Dest(e) => e.into(),
         }
     }
 }
 
+/// Copies the elements in `src_range` of `src` into `dst`, starting at index
+/// `dst_start`.
+///
+/// If either range is outside its lease, this returns
+/// `LeaseError::OutOfRange` for that side without copying anything. Otherwise,
+/// if copying fails partway, some of `dst` may already have been written.
+pub fn copy_lease<A, B, T>(
+    src: &Leased<A, [T]>,
+    src_range: Range<usize>,
+    dst: &Leased<B, [T]>,
+    dst_start: usize,
+) -> Result<(), CopyError>
+where
+    A: AttributeRead,
+    B: AttributeWrite,
+    T: Sized + Copy + FromBytes + AsBytes,
+{
+    let len = src_range.end.wrapping_sub(src_range.start);
+    let (src_offset, bytes) = src
+        .range_bytes(&src_range, len)
+        .map_err(CopyError::Source)?;
+    let dst_range = dst_start..dst_start.saturating_add(len);
+    let (dst_offset, _) =
+        dst.range_bytes(&dst_range, len).map_err(CopyError::Dest)?;
+    copy_bytes(
+        bytes,
+        |at, buf| read_bytes(src, src_offset + at, buf),
+        |at, buf| write_bytes(dst, dst_offset + at, buf),
+    )
+}
+
+/// Reads the bytes in `src_range` of `src`, passing them to `sink` in pieces
+/// of at most `COPY_BUFFER_SIZE` bytes, in order.
+///
+/// If `src_range` is outside the lease, this returns `LeaseError::OutOfRange`
+/// without calling `sink`. Copying stops at the first error from either side.
+pub fn copy_from_lease<A: AttributeRead, E>(
+    src: &Leased<A, [u8]>,
+    src_range: Range<usize>,
+    mut sink: impl FnMut(&[u8]) -> Result<(), E>,
+) -> Result<(), CopyError<LeaseError, E>> {
+    let len = src_range.end.wrapping_sub(src_range.start);
+    let (src_offset, bytes) = src
+        .range_bytes(&src_range, len)
+        .map_err(CopyError::Source)?;
+    copy_bytes(
+        bytes,
+        |at, buf| read_bytes(src, src_offset + at, buf),
+        |_, buf| sink(buf),
+    )
+}
+
+/// Writes bytes from `source` into `dst_range` of `dst`. `source` is asked to
+/// fill buffers of at most `COPY_BUFFER_SIZE` bytes, in order.
+///
+/// If `dst_range` is outside the lease, this returns `LeaseError::OutOfRange`
+/// without calling `source`. Copying stops at the first error from either
+/// side.
+pub fn copy_to_lease<B: AttributeWrite, E>(
+    mut source: impl FnMut(&mut [u8]) -> Result<(), E>,
+    dst: &Leased<B, [u8]>,
+    dst_range: Range<usize>,
+) -> Result<(), CopyError<E, LeaseError>> {
+    let len = dst_range.end.wrapping_sub(dst_range.start);
+    let (dst_offset, bytes) =
+        dst.range_bytes(&dst_range, len).map_err(CopyError::Dest)?;
+    copy_bytes(
+        bytes,
+        |_, buf| source(buf),
+        |at, buf| write_bytes(dst, dst_offset + at, buf),
+    )
+}
+
+/// Internal implementation factor for the copy functions: moves `len` bytes
+/// through a stack buffer, passing `read` and `write` the offset of each piece
+/// from the start of the copy.
+fn copy_bytes<S, D>(
+    len: usize,
+    mut read: impl FnMut(usize, &mut [u8]) -> Result<(), S>,
+    mut write: impl FnMut(usize, &[u8]) -> Result<(), D>,
+) -> Result<(), CopyError<S, D>> {
+    let mut buf = [0; COPY_BUFFER_SIZE];
+    let mut at = 0;
+    while at < len {
+        let piece = &mut buf[..(len - at).min(COPY_BUFFER_SIZE)];
+        read(at, piece).map_err(CopyError::Source)?;
+        write(at, piece).map_err(CopyError::Dest)?;
+        at += piece.len();
+    }
+    Ok(())
+}
+
+fn read_bytes<A: Attribute, T: ?Sized>(
+    lease: &Leased<A, T>,
+    offset: usize,
+    buf: &mut [u8],
+) -> Result<(), LeaseError> {
+    let (rc, len) = sys_borrow_read(lease.lender, lease.index, offset, buf);
+    transfer_result(rc, len, buf.len())
+}
+
+fn write_bytes<A: Attribute, T: ?Sized>(
+    lease: &Leased<A, T>,
+    offset: usize,
+    buf: &[u8],
+) -> Result<(), LeaseError> {
+    let (rc, len) = sys_borrow_write(lease.lender, lease.index, offset, buf);
+    transfer_result(rc, len, buf.len())
+}
+
 /// A `T` that has had its length checked to be no greater than `N`.
 ///
 /// While this type is written generically, it's only used in practice with `T`
@@ -713,3 +1494,197 @@ impl<T, const N: usize> core::ops::DerefMut for LenLimit<T, N> {
         &mut self.0
     }
 }
+
+/// A `T` that has had its length checked to be at least `N`.
+///
+/// Like `LenLimit`, this is only used in practice with leased slices: either
+/// `Leased<A, [E]>`, or, for leases with both limits,
+/// `LenLimit<Leased<A, [E]>, MAX>`. It implements `Deref` and `DerefMut` in the
+/// same way.
+pub struct MinLen<T, const N: usize>(T);
+
+impl<A: Attribute, T, const N: usize> TryFrom<Leased<A, [T]>>
+    for MinLen<Leased<A, [T]>, N>
+{
+    type Error = ();
+
+    fn try_from(x: Leased<A, [T]>) -> Result<Self, Self::Error> {
+        if x.len() >= N {
+            Ok(Self(x))
+        } else {
+            Err(())
+        }
+    }
+}
+
+impl<A: Attribute, T, const M: usize, const N: usize>
+    TryFrom<LenLimit<Leased<A, [T]>, M>>
+    for MinLen<LenLimit<Leased<A, [T]>, M>, N>
+{
+    type Error = ();
+
+    fn try_from(x: LenLimit<Leased<A, [T]>, M>) -> Result<Self, Self::Error> {
+        if x.len() >= N {
+            Ok(Self(x))
+        } else {
+            Err(())
+        }
+    }
+}
+
+impl<T, const N: usize> core::ops::Deref for MinLen<T, N> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<T, const N: usize> core::ops::DerefMut for MinLen<T, N> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+/// Error returned by `BoundedVec::extend_from_slice` when the values don't
+/// fit.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct CapacityError {
+    /// Number of values that were to be appended.
+    pub needed: usize,
+    /// Number of values there was room for.
+    pub available: usize,
+}
+
+/// Up to `N` values of type `T`, stored inline. This is what servers return,
+/// and clients receive, for operations with a variable-length reply, declared
+/// with an `ok` type of `[T; ..N]`.
+///
+/// Only the values present are sent in the reply. `BoundedVec` implements
+/// `Deref` to `[T]`, so it can be used much like a slice.
+#[derive(Copy, Clone)]
+pub struct BoundedVec<T, const N: usize> {
+    items: [T; N],
+    len: usize,
+}
+
+impl<T: Copy + FromBytes, const N: usize> BoundedVec<T, N> {
+    /// Creates an empty `BoundedVec`.
+    pub fn new() -> Self {
+        Self {
+            items: FromBytes::new_zeroed(),
+            len: 0,
+        }
+    }
+
+    /// Creates a `BoundedVec` holding a copy of `values`, or returns `None` if
+    /// there are more than `N` of them.
+    pub fn from_slice(values: &[T]) -> Option<Self> {
+        let mut v = Self::new();
+        v.extend_from_slice(values).ok()?;
+        Some(v)
+    }
+
+    /// Decodes a `BoundedVec` from the bytes of a reply. Returns `None` if
+    /// `bytes` isn't a whole number of `T`s, or holds more than `N` of them.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let size = core::mem::size_of::<T>();
+        if bytes.len().checked_rem(size) != Some(0) || bytes.len() / size > N {
+            return None;
+        }
+        let mut v = Self::new();
+        for chunk in bytes.chunks_exact(size) {
+            v.items[v.len] = T::read_from(chunk)?;
+            v.len += 1;
+        }
+        Some(v)
+    }
+
+    /// Appends `value`, or returns it if the `BoundedVec` is full.
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        match self.items.get_mut(self.len) {
+            Some(slot) => {
+                *slot = value;
+                self.len += 1;
+                Ok(())
+            }
+            None => Err(value),
+        }
+    }
+
+    /// Appends all of `values`, or returns a `CapacityError` without
+    /// appending any if there isn't room for them all.
+    pub fn extend_from_slice(
+        &mut self,
+        values: &[T],
+    ) -> Result<(), CapacityError> {
+        let end = self.len + values.len();
+        self.items
+            .get_mut(self.len..end)
+            .ok_or(CapacityError {
+                needed: values.len(),
+                available: N - self.len,
+            })?
+            .copy_from_slice(values);
+        self.len = end;
+        Ok(())
+    }
+}
+
+impl<T, const N: usize> BoundedVec<T, N> {
+    /// Returns the maximum number of values this can hold.
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Shortens the `BoundedVec` to `len` values, if it's longer.
+    pub fn truncate(&mut self, len: usize) {
+        self.len = self.len.min(len);
+    }
+
+    /// Returns the values present.
+    pub fn as_slice(&self) -> &[T] {
+        &self.items[..self.len]
+    }
+
+    /// Returns the values present, mutably.
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        &mut self.items[..self.len]
+    }
+}
+
+impl<T: Copy + FromBytes, const N: usize> Default for BoundedVec<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> core::ops::Deref for BoundedVec<T, N> {
+    type Target = [T];
+
+    fn deref(&self) -> &Self::Target {
+        self.as_slice()
+    }
+}
+
+impl<T, const N: usize> core::ops::DerefMut for BoundedVec<T, N> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.as_mut_slice()
+    }
+}
+
+impl<T: core::fmt::Debug, const N: usize> core::fmt::Debug
+    for BoundedVec<T, N>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        self.as_slice().fmt(f)
+    }
+}
+
+impl<T: PartialEq, const N: usize> PartialEq for BoundedVec<T, N> {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl<T: Eq, const N: usize> Eq for BoundedVec<T, N> {}
@@ -0,0 +1,698 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! In-process simulation of the parts of the Hubris kernel that Idol uses, so
+//! that servers and clients can be run in tests on a development machine.
+//!
+//! This module provides the subset of `userlib` that this crate and generated
+//! code depend on. With the `std` feature, this crate uses it in place of
+//! `userlib`. To point generated code at it, set the userlib path to
+//! `idol_runtime::sim`, using `Generator::userlib_path` or the `userlib`
+//! setting of the macros. (Generated code also derives `FromPrimitive`, which
+//! needs `num-traits` as a dependency of the including crate.)
+//!
+//! Each simulated task runs on its own thread, created by `Sim::spawn`, or on
+//! the calling thread, with `Sim::run`. Syscalls behave as they do on Hubris:
+//! `sys_send` blocks until the server replies, leases give the server access to
+//! the client's memory for as long as the client is blocked, and tasks have
+//! generations, so that restarting a server with `Sim::restart` hands its
+//! clients dead codes.
+//!
+//! ```ignore
+//! let sim = idol_runtime::sim::Sim::new();
+//! let server = sim.spawn(|| {
+//!     let mut buffer = [0; INCOMING_SIZE];
+//!     let mut server = MyServer::default();
+//!     loop {
+//!         idol_runtime::dispatch(&mut buffer, &mut server);
+//!     }
+//! });
+//! sim.run(|| {
+//!     let client = MyInterface::from(server);
+//!     assert_eq!(client.my_op(1), Ok(2));
+//! });
+//! ```
+
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+
+pub use num_derive::FromPrimitive;
+pub use num_traits::FromPrimitive;
+
+/// Number of low bits of a `TaskId` that hold the task index.
+const INDEX_BITS: u32 = 10;
+const INDEX_MASK: u16 = (1 << INDEX_BITS) - 1;
+/// Generations wrap at this many bits, the space left in a `TaskId`.
+const GENERATION_MASK: u8 = (1 << (16 - INDEX_BITS)) - 1;
+
+/// Response codes with these bits set report that the peer has restarted, with
+/// its new generation in the low byte.
+const DEAD_CODE: u32 = 0xFFFF_FF00;
+
+/// Response code for borrow operations that the lease doesn't permit, such as
+/// reading from a write-only lease. Hubris faults the server in some of these
+/// cases; we report them, which the runtime treats as a failed access.
+const LEASE_DEFECT: u32 = 1;
+
+/// Names a task, and a particular generation (incarnation) of it.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct TaskId(pub u16);
+
+impl TaskId {
+    /// Sender of notifications.
+    pub const KERNEL: Self = Self(0xFFFF);
+
+    pub fn for_index_and_gen(index: usize, gen: Generation) -> Self {
+        Self((index as u16 & INDEX_MASK) | u16::from(gen.0) << INDEX_BITS)
+    }
+
+    pub fn index(&self) -> usize {
+        usize::from(self.0 & INDEX_MASK)
+    }
+
+    pub fn generation(&self) -> Generation {
+        Generation((self.0 >> INDEX_BITS) as u8)
+    }
+}
+
+/// Counts restarts of a task.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct Generation(u8);
+
+impl Generation {
+    fn next(self) -> Self {
+        Self(self.0.wrapping_add(1) & GENERATION_MASK)
+    }
+}
+
+impl From<u8> for Generation {
+    fn from(x: u8) -> Self {
+        Self(x & GENERATION_MASK)
+    }
+}
+
+impl From<Generation> for u8 {
+    fn from(x: Generation) -> Self {
+        x.0
+    }
+}
+
+/// Checks whether a response code reports that the peer restarted, and if so,
+/// returns its new generation.
+pub fn extract_new_generation(code: u32) -> Option<Generation> {
+    if code & DEAD_CODE == DEAD_CODE {
+        Some(Generation::from(code as u8))
+    } else {
+        None
+    }
+}
+
+fn dead_code(gen: Generation) -> u32 {
+    DEAD_CODE | u32::from(gen.0)
+}
+
+/// Description of a message received by `sys_recv`.
+#[derive(Clone, Debug)]
+pub struct RecvMessage {
+    pub sender: TaskId,
+    pub operation: u32,
+    /// Length of the message as sent, which may exceed the buffer it was
+    /// received into.
+    pub message_len: usize,
+    pub response_capacity: usize,
+    pub lease_count: usize,
+}
+
+/// Access granted by a lease.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct LeaseAttributes(u32);
+
+impl LeaseAttributes {
+    pub const READ: Self = Self(1);
+    pub const WRITE: Self = Self(2);
+
+    pub fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+/// Description of a lease, as returned by `sys_borrow_info`.
+#[derive(Copy, Clone, Debug)]
+pub struct BorrowInfo {
+    pub attributes: LeaseAttributes,
+    pub len: usize,
+}
+
+/// Memory lent to the recipient of a message.
+pub struct Lease<'a> {
+    raw: RawLease,
+    _marker: PhantomData<&'a mut [u8]>,
+}
+
+impl<'a> Lease<'a> {
+    pub fn read_only(x: &'a [u8]) -> Self {
+        Self::new(x.as_ptr() as *mut u8, x.len(), LeaseAttributes::READ)
+    }
+
+    pub fn read_write(x: &'a mut [u8]) -> Self {
+        let attributes =
+            LeaseAttributes(LeaseAttributes::READ.0 | LeaseAttributes::WRITE.0);
+        Self::new(x.as_mut_ptr(), x.len(), attributes)
+    }
+
+    pub fn write_only(x: &'a mut [u8]) -> Self {
+        Self::new(x.as_mut_ptr(), x.len(), LeaseAttributes::WRITE)
+    }
+
+    fn new(ptr: *mut u8, len: usize, attributes: LeaseAttributes) -> Self {
+        Self {
+            raw: RawLease {
+                ptr,
+                len,
+                attributes,
+            },
+            _marker: PhantomData,
+        }
+    }
+}
+
+/// A lease as recorded by the kernel, without its lifetime.
+#[derive(Copy, Clone)]
+struct RawLease {
+    ptr: *mut u8,
+    len: usize,
+    attributes: LeaseAttributes,
+}
+
+// Raw leases are only dereferenced by the server's thread while the lender is
+// blocked in `sys_send`, which keeps the leased memory borrowed, and with the
+// kernel lock held, so that the lender can't be released while we're copying.
+unsafe impl Send for RawLease {}
+
+/// What a task is doing, as far as IPC is concerned.
+enum State {
+    Running,
+    /// Blocked in `sys_send`, waiting for `target` to receive the message.
+    Sending {
+        target: TaskId,
+        operation: u16,
+        message: Vec<u8>,
+        response_capacity: usize,
+        leases: Vec<RawLease>,
+    },
+    /// Blocked in `sys_send`, with the message received by `server`, waiting
+    /// for the reply.
+    AwaitingReply {
+        server: TaskId,
+        response_capacity: usize,
+        leases: Vec<RawLease>,
+    },
+    /// Still blocked in `sys_send`, but with a reply ready to collect.
+    Replied {
+        code: u32,
+        message: Vec<u8>,
+    },
+}
+
+struct Task {
+    generation: Generation,
+    state: State,
+    /// Posted notification bits that haven't been received yet.
+    notifications: u32,
+}
+
+struct Kernel {
+    tasks: Vec<Task>,
+    /// Set when the `Sim` is dropped, to release all blocked tasks.
+    shutdown: bool,
+}
+
+impl Kernel {
+    /// Bumps the generation of the task at `index`, failing any operations
+    /// that other tasks have in progress with it, and returns its new id.
+    fn restart(&mut self, index: usize) -> TaskId {
+        let task = &mut self.tasks[index];
+        task.generation = task.generation.next();
+        task.state = State::Running;
+        task.notifications = 0;
+        let code = dead_code(task.generation);
+
+        for task in &mut self.tasks {
+            let peer = match &task.state {
+                State::Sending { target, .. } => target,
+                State::AwaitingReply { server, .. } => server,
+                _ => continue,
+            };
+            if peer.index() == index {
+                task.state = State::Replied {
+                    code,
+                    message: vec![],
+                };
+            }
+        }
+        TaskId::for_index_and_gen(index, self.tasks[index].generation)
+    }
+
+    /// Finds lease `index` of `lender`, which must be waiting for a reply from
+    /// `server`, and check that it grants `atts`.
+    fn lease(
+        &self,
+        server: TaskId,
+        lender: TaskId,
+        index: usize,
+        atts: LeaseAttributes,
+    ) -> Result<RawLease, u32> {
+        let task = self.tasks.get(lender.index()).ok_or(LEASE_DEFECT)?;
+        if task.generation != lender.generation() {
+            return Err(dead_code(task.generation));
+        }
+        match &task.state {
+            State::AwaitingReply {
+                server: s, leases, ..
+            } if *s == server => leases
+                .get(index)
+                .filter(|lease| lease.attributes.contains(atts))
+                .copied()
+                .ok_or(LEASE_DEFECT),
+            _ => Err(LEASE_DEFECT),
+        }
+    }
+}
+
+struct Shared {
+    kernel: Mutex<Kernel>,
+    /// Signalled whenever any task's state changes.
+    changed: Condvar,
+}
+
+/// Unwinding payload used to end the thread of a task that's been restarted.
+struct Restarted;
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Kernel> {
+        // A task that panics while holding the lock, such as a server that
+        // replies with too much data, has been killed, which leaves the kernel
+        // consistent.
+        self.kernel.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Locks the kernel on behalf of `me`, ending the calling thread if `me`
+    /// has since been restarted.
+    fn lock_alive(&self, me: TaskId) -> MutexGuard<'_, Kernel> {
+        Self::check_alive(self.lock(), me)
+    }
+
+    /// Waits for something to change, then relocks as `lock_alive` does.
+    fn wait<'a>(
+        &self,
+        kernel: MutexGuard<'a, Kernel>,
+        me: TaskId,
+    ) -> MutexGuard<'a, Kernel> {
+        let kernel = self
+            .changed
+            .wait(kernel)
+            .unwrap_or_else(PoisonError::into_inner);
+        Self::check_alive(kernel, me)
+    }
+
+    fn check_alive(
+        kernel: MutexGuard<'_, Kernel>,
+        me: TaskId,
+    ) -> MutexGuard<'_, Kernel> {
+        if kernel.shutdown
+            || kernel.tasks[me.index()].generation != me.generation()
+        {
+            drop(kernel);
+            std::panic::resume_unwind(Box::new(Restarted));
+        }
+        kernel
+    }
+}
+
+/// The task that the current thread is running as, if any.
+struct Current {
+    shared: Arc<Shared>,
+    id: TaskId,
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Current>> = RefCell::new(None);
+}
+
+fn current() -> (Arc<Shared>, TaskId) {
+    CURRENT.with(|c| {
+        let c = c.borrow();
+        let c = c.as_ref().expect(
+            "syscall made outside a simulated task; use Sim::spawn or Sim::run",
+        );
+        (c.shared.clone(), c.id)
+    })
+}
+
+/// Restarts the current task when it finishes, unless something else already
+/// has, so that nothing is left waiting on it.
+struct Exit {
+    shared: Arc<Shared>,
+    id: TaskId,
+}
+
+impl Drop for Exit {
+    fn drop(&mut self) {
+        CURRENT.with(|c| c.borrow_mut().take());
+        let mut kernel = self.shared.lock();
+        if kernel.tasks[self.id.index()].generation == self.id.generation() {
+            kernel.restart(self.id.index());
+            self.shared.changed.notify_all();
+        }
+    }
+}
+
+fn run_as<R>(shared: Arc<Shared>, id: TaskId, body: impl FnOnce() -> R) -> R {
+    CURRENT.with(|c| {
+        let prev = c.borrow_mut().replace(Current {
+            shared: shared.clone(),
+            id,
+        });
+        assert!(prev.is_none(), "thread is already a simulated task");
+    });
+    let _exit = Exit { shared, id };
+    body()
+}
+
+/// A simulated system: a set of tasks that can exchange messages.
+///
+/// Dropping the `Sim` ends any of its tasks that are blocked in syscalls.
+pub struct Sim {
+    shared: Arc<Shared>,
+}
+
+impl Default for Sim {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sim {
+    /// Creates a system with no tasks.
+    pub fn new() -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                kernel: Mutex::new(Kernel {
+                    tasks: vec![],
+                    shutdown: false,
+                }),
+                changed: Condvar::new(),
+            }),
+        }
+    }
+
+    fn add_task(&self) -> TaskId {
+        let mut kernel = self.shared.lock();
+        let index = kernel.tasks.len();
+        assert!(index < usize::from(INDEX_MASK), "too many tasks");
+        kernel.tasks.push(Task {
+            generation: Generation::default(),
+            state: State::Running,
+            notifications: 0,
+        });
+        TaskId::for_index_and_gen(index, Generation::default())
+    }
+
+    /// Starts a new task running `body` on its own thread, returning its id.
+    ///
+    /// If `body` returns or panics, the task is treated as having crashed: its
+    /// generation is bumped, and anyone waiting on it gets a dead code.
+    pub fn spawn(&self, body: impl FnOnce() + Send + 'static) -> TaskId {
+        let id = self.add_task();
+        let shared = self.shared.clone();
+        std::thread::spawn(move || run_as(shared, id, body));
+        id
+    }
+
+    /// Runs `body` as a new task on the calling thread, typically to act as a
+    /// client of tasks created with `spawn`.
+    pub fn run<R>(&self, body: impl FnOnce() -> R) -> R {
+        let id = self.add_task();
+        run_as(self.shared.clone(), id, body)
+    }
+
+    /// Restarts `task`, running `body` as its new incarnation on a new thread,
+    /// and returns its new id.
+    ///
+    /// Messages in progress with the old incarnation fail with a dead code, as
+    /// do future messages sent to its old id. The old thread is ended the next
+    /// time it makes a syscall.
+    pub fn restart(
+        &self,
+        task: TaskId,
+        body: impl FnOnce() + Send + 'static,
+    ) -> TaskId {
+        let id = self.shared.lock().restart(task.index());
+        self.shared.changed.notify_all();
+        let shared = self.shared.clone();
+        std::thread::spawn(move || run_as(shared, id, body));
+        id
+    }
+
+    /// Posts notification `bits` to `task`, as an interrupt or another task
+    /// would.
+    pub fn post(&self, task: TaskId, bits: u32) {
+        let mut kernel = self.shared.lock();
+        if let Some(t) = kernel.tasks.get_mut(task.index()) {
+            t.notifications |= bits;
+        }
+        self.shared.changed.notify_all();
+    }
+}
+
+impl Drop for Sim {
+    fn drop(&mut self) {
+        self.shared.lock().shutdown = true;
+        self.shared.changed.notify_all();
+    }
+}
+
+/// Sends a message to `target` and waits for the reply, as on Hubris.
+///
+/// Returns the response code and the length of the reply message, which has
+/// been copied into `incoming`.
+pub fn sys_send(
+    target: TaskId,
+    operation: u16,
+    outgoing: &[u8],
+    incoming: &mut [u8],
+    leases: &[Lease<'_>],
+) -> (u32, usize) {
+    let (shared, me) = current();
+    let mut kernel = shared.lock_alive(me);
+
+    assert_ne!(target.index(), me.index(), "task sent a message to itself");
+    let dest = kernel
+        .tasks
+        .get(target.index())
+        .unwrap_or_else(|| panic!("message sent to nonexistent {:?}", target));
+    if dest.generation != target.generation() {
+        return (dead_code(dest.generation), 0);
+    }
+
+    kernel.tasks[me.index()].state = State::Sending {
+        target,
+        operation,
+        message: outgoing.to_vec(),
+        response_capacity: incoming.len(),
+        leases: leases.iter().map(|l| l.raw).collect(),
+    };
+    shared.changed.notify_all();
+
+    loop {
+        kernel = shared.wait(kernel, me);
+        let state = &mut kernel.tasks[me.index()].state;
+        if let State::Replied { code, message } = state {
+            // `sys_reply` checked that this fits.
+            incoming[..message.len()].copy_from_slice(message);
+            let result = (*code, message.len());
+            *state = State::Running;
+            return result;
+        }
+    }
+}
+
+/// Waits for a message or notification, as on Hubris.
+///
+/// Notifications in `mask` are delivered as messages from `TaskId::KERNEL`,
+/// with the bits as the operation. If `source` is given, only messages from
+/// that task are accepted, and if it has restarted, this returns its dead code
+/// as an error.
+pub fn sys_recv(
+    buffer: &mut [u8],
+    mask: u32,
+    source: Option<TaskId>,
+) -> Result<RecvMessage, u32> {
+    let (shared, me) = current();
+    let mut kernel = shared.lock_alive(me);
+
+    loop {
+        let task = &mut kernel.tasks[me.index()];
+        let bits = task.notifications & mask;
+        if bits != 0 {
+            task.notifications &= !bits;
+            return Ok(RecvMessage {
+                sender: TaskId::KERNEL,
+                operation: bits,
+                message_len: 0,
+                response_capacity: 0,
+                lease_count: 0,
+            });
+        }
+
+        if let Some(source) = source {
+            let gen = kernel.tasks[source.index()].generation;
+            if gen != source.generation() {
+                return Err(dead_code(gen));
+            }
+        }
+
+        let sender = kernel.tasks.iter().enumerate().position(|(i, t)| {
+            let eligible = !matches!(source, Some(s) if s.index() != i);
+            let sending_to_me = matches!(
+                &t.state,
+                State::Sending { target, .. } if target.index() == me.index()
+            );
+            eligible && sending_to_me
+        });
+
+        if let Some(index) = sender {
+            let task = &mut kernel.tasks[index];
+            let sender = TaskId::for_index_and_gen(index, task.generation);
+            let state = std::mem::replace(&mut task.state, State::Running);
+            if let State::Sending {
+                operation,
+                message,
+                response_capacity,
+                leases,
+                ..
+            } = state
+            {
+                let n = message.len().min(buffer.len());
+                buffer[..n].copy_from_slice(&message[..n]);
+                let rm = RecvMessage {
+                    sender,
+                    operation: u32::from(operation),
+                    message_len: message.len(),
+                    response_capacity,
+                    lease_count: leases.len(),
+                };
+                task.state = State::AwaitingReply {
+                    server: me,
+                    response_capacity,
+                    leases,
+                };
+                return Ok(rm);
+            }
+            unreachable!();
+        }
+
+        kernel = shared.wait(kernel, me);
+    }
+}
+
+/// Replies to a task that's waiting for a reply from the caller, as on Hubris.
+///
+/// Replies to tasks that aren't waiting, for instance because they've been
+/// restarted, are ignored. A reply that's too long for the sender's buffer is a
+/// fault, and panics.
+pub fn sys_reply(peer: TaskId, code: u32, message: &[u8]) {
+    let (shared, me) = current();
+    let mut kernel = shared.lock_alive(me);
+
+    let task = match kernel.tasks.get_mut(peer.index()) {
+        Some(t) if t.generation == peer.generation() => t,
+        _ => return,
+    };
+    match &task.state {
+        State::AwaitingReply {
+            server,
+            response_capacity,
+            ..
+        } if *server == me => {
+            assert!(
+                message.len() <= *response_capacity,
+                "reply of {} bytes to {:?} exceeds its {} byte buffer",
+                message.len(),
+                peer,
+                response_capacity
+            );
+        }
+        _ => return,
+    }
+    task.state = State::Replied {
+        code,
+        message: message.to_vec(),
+    };
+    shared.changed.notify_all();
+}
+
+/// Describes lease `index` of `lender`, which must be waiting for a reply from
+/// the caller.
+pub fn sys_borrow_info(lender: TaskId, index: usize) -> Option<BorrowInfo> {
+    let (shared, me) = current();
+    let kernel = shared.lock_alive(me);
+    let lease = kernel.lease(me, lender, index, LeaseAttributes(0)).ok()?;
+    Some(BorrowInfo {
+        attributes: lease.attributes,
+        len: lease.len,
+    })
+}
+
+/// Copies from lease `index` of `lender`, starting `offset` bytes in, into
+/// `dest`. Returns a response code, and the number of bytes copied.
+pub fn sys_borrow_read(
+    lender: TaskId,
+    index: usize,
+    offset: usize,
+    dest: &mut [u8],
+) -> (u32, usize) {
+    let (shared, me) = current();
+    let kernel = shared.lock_alive(me);
+    let lease = match kernel.lease(me, lender, index, LeaseAttributes::READ) {
+        Ok(lease) if offset <= lease.len => lease,
+        Ok(_) => return (LEASE_DEFECT, 0),
+        Err(code) => return (code, 0),
+    };
+    let n = (lease.len - offset).min(dest.len());
+    // Safety: see `RawLease`. We hold the kernel lock, and have checked that
+    // the range is within the lease.
+    unsafe {
+        std::ptr::copy_nonoverlapping(
+            lease.ptr.add(offset),
+            dest.as_mut_ptr(),
+            n,
+        );
+    }
+    (0, n)
+}
+
+/// Copies `src` into lease `index` of `lender`, starting `offset` bytes in.
+/// Returns a response code, and the number of bytes copied.
+pub fn sys_borrow_write(
+    lender: TaskId,
+    index: usize,
+    offset: usize,
+    src: &[u8],
+) -> (u32, usize) {
+    let (shared, me) = current();
+    let kernel = shared.lock_alive(me);
+    let lease = match kernel.lease(me, lender, index, LeaseAttributes::WRITE) {
+        Ok(lease) if offset <= lease.len => lease,
+        Ok(_) => return (LEASE_DEFECT, 0),
+        Err(code) => return (code, 0),
+    };
+    let n = (lease.len - offset).min(src.len());
+    // Safety: as for `sys_borrow_read`.
+    unsafe {
+        std::ptr::copy_nonoverlapping(src.as_ptr(), lease.ptr.add(offset), n);
+    }
+    (0, n)
+}
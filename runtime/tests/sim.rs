@@ -0,0 +1,594 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Drives a generated client and server through `dispatch` using the simulated
+//! kernel.
+
+use idol_runtime::sim::{Lease, RecvMessage, Sim, TaskId};
+use idol_runtime::{
+    BoundedVec, ClientError, CopyError, LeaseError, Leased, LenLimit, MinLen,
+    RequestError, FINGERPRINT_OP, R, REFLECT_OP, W,
+};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum EchoError {
+    TooShort = 1,
+    ServerRestarted = 2,
+}
+
+impl TryFrom<u32> for EchoError {
+    type Error = ();
+
+    fn try_from(x: u32) -> Result<Self, ()> {
+        match x {
+            1 => Ok(Self::TooShort),
+            x if idol_runtime::sim::extract_new_generation(x).is_some() => {
+                Ok(Self::ServerRestarted)
+            }
+            _ => Err(()),
+        }
+    }
+}
+
+impl From<EchoError> for u16 {
+    fn from(x: EchoError) -> Self {
+        x as u16
+    }
+}
+
+mod client {
+    use super::*;
+    idol_macros::client!(
+        "tests/echo.idol",
+        userlib = "idol_runtime::sim",
+        handshake = true,
+        api_trait = true,
+        mock = true,
+    );
+}
+
+mod server {
+    use super::*;
+    idol_macros::server!(
+        "tests/echo.idol",
+        InOrder,
+        userlib = "idol_runtime::sim",
+        handshake = true,
+        reflection = true,
+        fuzz_harness = true,
+        counters = true,
+    );
+}
+
+// Ping is a different interface, so its client's handshake should fail
+// against an Echo server. Its server doesn't take part in the handshake.
+mod ping_client {
+    use super::*;
+    idol_macros::client!(
+        "tests/ping.idol",
+        userlib = "idol_runtime::sim",
+        handshake = true,
+    );
+}
+
+mod ping_server {
+    use super::*;
+    idol_macros::server!(
+        "tests/ping.idol",
+        InOrder,
+        userlib = "idol_runtime::sim",
+    );
+}
+
+struct EchoServer;
+
+impl server::InOrderEchoImpl for EchoServer {
+    fn add(
+        &mut self,
+        _: &RecvMessage,
+        a: u32,
+        b: u32,
+    ) -> Result<u32, RequestError<EchoError>> {
+        Ok(a.wrapping_add(b))
+    }
+
+    fn reverse(
+        &mut self,
+        _: &RecvMessage,
+        src: MinLen<LenLimit<Leased<R, [u8]>, 16>, 1>,
+        dst: Leased<W, [u8]>,
+    ) -> Result<u32, RequestError<EchoError>> {
+        let n = src.len();
+        let mut buf = [0; 16];
+        src.read_range(0..n, &mut buf[..n])
+            .map_err(RequestError::lease)?;
+        buf[..n].reverse();
+        dst.try_write_range(0..n, &buf[..n]).map_err(|e| match e {
+            LeaseError::OutOfRange => EchoError::TooShort.into(),
+            e => RequestError::lease(e),
+        })?;
+        Ok(n as u32)
+    }
+
+    fn reset(
+        &mut self,
+        _: &RecvMessage,
+    ) -> Result<(), RequestError<EchoError>> {
+        Ok(())
+    }
+
+    fn sum(
+        &mut self,
+        _: &RecvMessage,
+        src: Leased<R, [u8]>,
+    ) -> Result<u32, RequestError<EchoError>> {
+        // A small buffer, so that most leases take several chunks.
+        let mut buf = [0; 3];
+        let mut chunks = src.chunks(&mut buf);
+        let mut sum = 0u32;
+        while let Some(chunk) = chunks.next_chunk() {
+            let (_, chunk) = chunk.map_err(RequestError::lease)?;
+            sum += chunk.iter().map(|&b| u32::from(b)).sum::<u32>();
+        }
+        Ok(sum)
+    }
+
+    fn fill(
+        &mut self,
+        _: &RecvMessage,
+        value: u8,
+        dst: Leased<W, [u8]>,
+    ) -> Result<u32, RequestError<EchoError>> {
+        let mut buf = [0; 3];
+        let mut writer = dst.writer(&mut buf);
+        while writer.remaining() != 0 {
+            writer.write(&[value, value]).map_err(RequestError::lease)?;
+        }
+        writer.flush().map_err(RequestError::lease)?;
+        Ok(writer.position() as u32)
+    }
+
+    fn copy(
+        &mut self,
+        _: &RecvMessage,
+        at: u32,
+        src: Leased<R, [u8]>,
+        dst: Leased<W, [u8]>,
+    ) -> Result<(), RequestError<EchoError>> {
+        idol_runtime::copy_lease(&src, 0..src.len(), &dst, at as usize).map_err(
+            |e| match e {
+                CopyError::Dest(LeaseError::OutOfRange) => {
+                    EchoError::TooShort.into()
+                }
+                e => RequestError::Fail(e.into()),
+            },
+        )
+    }
+
+    fn count_chars(
+        &mut self,
+        _: &RecvMessage,
+        text: Leased<R, str>,
+    ) -> Result<u32, RequestError<EchoError>> {
+        let mut buf = [0; 32];
+        let text = text.read_str(&mut buf).map_err(RequestError::lease)?;
+        Ok(text.chars().count() as u32)
+    }
+
+    fn take(
+        &mut self,
+        _: &RecvMessage,
+        count: u32,
+    ) -> Result<BoundedVec<u16, 4>, RequestError<EchoError>> {
+        let values = [10, 20, 30, 40];
+        let values = values.get(..count as usize).ok_or(EchoError::TooShort)?;
+        Ok(BoundedVec::from_slice(values).unwrap())
+    }
+
+    fn sender_is_task(&self, sender: TaskId, task: &str) -> bool {
+        // Tasks are numbered in the order they start; `callers_are_checked`
+        // starts the supervisor third.
+        task == "supervisor" && sender.index() == 2
+    }
+}
+
+struct PingServer;
+
+impl ping_server::InOrderPingImpl for PingServer {
+    fn ping(&mut self, _: &RecvMessage) -> Result<(), RequestError<EchoError>> {
+        Ok(())
+    }
+}
+
+fn serve_ping() {
+    let mut buffer = [0; ping_server::INCOMING_SIZE];
+    loop {
+        idol_runtime::dispatch(&mut buffer, &mut PingServer);
+    }
+}
+
+fn serve() {
+    let mut buffer = [0; server::INCOMING_SIZE];
+    loop {
+        idol_runtime::dispatch(&mut buffer, &mut EchoServer);
+    }
+}
+
+#[test]
+fn calls_reach_server() {
+    let sim = Sim::new();
+    let server = sim.spawn(serve);
+    sim.run(|| {
+        let echo = client::Echo::from(server);
+        assert_eq!(echo.add(2, 3), Ok(5));
+
+        let mut dst = [0; 4];
+        assert_eq!(echo.reverse(b"abc", &mut dst), Ok(3));
+        assert_eq!(&dst, b"cba\0");
+        assert_eq!(
+            echo.reverse(b"abc", &mut dst[..2]),
+            Err(EchoError::TooShort)
+        );
+    });
+}
+
+/// Stands in for code under test that uses the interface through its trait.
+fn add_then_reverse(echo: &impl client::EchoApi, dst: &mut [u8]) -> u32 {
+    let n = echo.add(1, 2).unwrap();
+    echo.reverse(&b"abcd"[..n as usize], dst).unwrap()
+}
+
+#[test]
+fn api_trait_is_implemented() {
+    let sim = Sim::new();
+    let server = sim.spawn(serve);
+    sim.run(|| {
+        let mut dst = [0; 4];
+        assert_eq!(add_then_reverse(&client::Echo::from(server), &mut dst), 3);
+        assert_eq!(&dst, b"cba\0");
+    });
+}
+
+#[test]
+fn mock_answers_and_records_calls() {
+    let mock = client::MockEcho::new();
+    mock.expect_add(|a, b| Ok(a * b)).expect_reverse(|_, dst| {
+        dst[0] = b'x';
+        Ok(1)
+    });
+
+    let mut dst = [0; 4];
+    assert_eq!(add_then_reverse(&mock, &mut dst), 1);
+    assert_eq!(&dst, b"x\0\0\0");
+
+    let adds = mock.take_add_calls();
+    assert_eq!(adds.len(), 1);
+    assert_eq!((adds[0].a, adds[0].b), (1, 2));
+    assert!(mock.take_add_calls().is_empty());
+    let reverses = mock.take_reverse_calls();
+    assert_eq!(reverses.len(), 1);
+    assert_eq!(reverses[0].src, b"ab");
+    mock.checkpoint();
+
+    // Expectations left over are reported by `checkpoint`.
+    mock.expect_add(|_, _| Ok(0));
+    let unused = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        mock.checkpoint()
+    }));
+    assert!(unused.is_err());
+}
+
+#[test]
+fn restart_produces_dead_code() {
+    let sim = Sim::new();
+    let server = sim.spawn(serve);
+    sim.run(|| {
+        let echo = client::Echo::from(server);
+        assert_eq!(echo.add(1, 1), Ok(2));
+
+        let restarted: TaskId = sim.restart(server, serve);
+        assert_ne!(restarted, server);
+
+        // The first call discovers the new generation, and the client uses it
+        // from then on.
+        assert_eq!(echo.add(1, 1), Err(EchoError::ServerRestarted));
+        assert_eq!(echo.add(1, 1), Ok(2));
+    });
+}
+
+#[test]
+fn handshake_checks_fingerprint() {
+    let sim = Sim::new();
+    let echo_server = sim.spawn(serve);
+    let ping_server = sim.spawn(serve_ping);
+    sim.run(|| {
+        assert!(client::Echo::from(echo_server).check_fingerprint());
+        assert!(!ping_client::Ping::from(echo_server).check_fingerprint());
+
+        let mut reply = [0; 8];
+        let (code, len) = idol_runtime::sim::sys_send(
+            echo_server,
+            FINGERPRINT_OP,
+            &[],
+            &mut reply,
+            &[],
+        );
+        assert_eq!((code, len), (0, 8));
+        assert_eq!(
+            u64::from_le_bytes(reply),
+            client::EchoOperation::FINGERPRINT
+        );
+
+        // Servers generated without the handshake don't know the operation.
+        let (code, _) = idol_runtime::sim::sys_send(
+            ping_server,
+            FINGERPRINT_OP,
+            &[],
+            &mut reply,
+            &[],
+        );
+        assert_eq!(code, ClientError::UnknownOperation as u32);
+    });
+}
+
+#[test]
+fn reflection_returns_definition() {
+    let sim = Sim::new();
+    let server = sim.spawn(serve);
+    sim.run(|| {
+        // Fetch in small chunks, to exercise the offset.
+        let mut definition = vec![];
+        loop {
+            let offset = definition.len() as u32;
+            let mut chunk = [0; 7];
+            let mut reply = [0; 8];
+            let (code, len) = idol_runtime::sim::sys_send(
+                server,
+                REFLECT_OP,
+                &offset.to_le_bytes(),
+                &mut reply,
+                &[Lease::write_only(&mut chunk)],
+            );
+            assert_eq!((code, len), (0, 8));
+            let total = u32::from_le_bytes(reply[..4].try_into().unwrap());
+            let copied = u32::from_le_bytes(reply[4..].try_into().unwrap());
+            definition.extend_from_slice(&chunk[..copied as usize]);
+            if definition.len() as u32 == total {
+                break;
+            }
+            assert_ne!(copied, 0);
+        }
+        assert_eq!(definition, include_bytes!("echo.idol"));
+    });
+}
+
+#[test]
+fn fuzz_inputs_are_checked() {
+    let inputs: &[&[u8]] = &[
+        &[],
+        &[0, 0, 1, 2, 3, 4, 5, 6, 7, 8],
+        &[1, 0, 3, 0],
+        &[1, 0, 17, 0],
+        &[1, 2, 1, 1, 4],
+        &[0, 4, 1, 2],
+        &[3, 2, 1, 0, 5],
+        &[0xFF, 0xFF, 0xFF],
+        &[0xFF, 0x10, 0],
+    ];
+    for input in inputs {
+        server::fuzz_echo(input);
+    }
+
+    // A fuzzer would find more; a fixed pseudo-random sample stands in for it.
+    let mut state = 0x2545_f491_u32;
+    for _ in 0..200 {
+        let mut input = vec![];
+        for _ in 0..state % 24 {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            input.push(state as u8);
+        }
+        server::fuzz_echo(&input);
+    }
+}
+
+impl idol_runtime::NotificationHandler for EchoServer {
+    fn current_notification_mask(&self) -> u32 {
+        1
+    }
+
+    fn handle_notification(&mut self, _bits: u32) {}
+}
+
+#[test]
+fn dispatch_n_rejects_truncated_messages() {
+    let sim = Sim::new();
+    let server = sim.spawn(|| {
+        let mut buffer = [0; server::INCOMING_SIZE];
+        loop {
+            idol_runtime::dispatch_n(&mut buffer, &mut EchoServer);
+        }
+    });
+    sim.run(|| {
+        sim.post(server, 1);
+
+        let oversized = [0; server::INCOMING_SIZE + 1];
+        let mut reply = [0; server::ADD_REPLY_SIZE];
+        let (rc, _) =
+            idol_runtime::sim::sys_send(server, 1, &oversized, &mut reply, &[]);
+        assert_eq!(rc, u32::from(idol_runtime::ClientError::BadMessage));
+
+        let echo = client::Echo::from(server);
+        assert_eq!(echo.add(2, 3), Ok(5));
+    });
+}
+
+#[test]
+fn observer_sees_calls() {
+    let counts = &server::ECHO_COUNTERS;
+
+    let sim = Sim::new();
+    let server = sim.spawn(move || {
+        let mut buffer = [0; server::INCOMING_SIZE];
+        loop {
+            idol_runtime::dispatch_observed(
+                &mut buffer,
+                &mut EchoServer,
+                counts,
+            );
+        }
+    });
+    sim.run(|| {
+        let echo = client::Echo::from(server);
+        assert_eq!(echo.add(2, 3), Ok(5));
+        let mut dst = [0; 2];
+        assert_eq!(echo.reverse(b"abc", &mut dst), Err(EchoError::TooShort));
+
+        let mut reply = [0; 4];
+        let (rc, _) =
+            idol_runtime::sim::sys_send(server, 99, &[], &mut reply, &[]);
+        assert_eq!(rc, u32::from(idol_runtime::ClientError::UnknownOperation));
+    });
+
+    assert_eq!(counts.add.calls.get(), 1);
+    assert_eq!(counts.add.errors.get(), 0);
+    assert_eq!(counts.reverse.calls.get(), 1);
+    assert_eq!(counts.reverse.errors.get(), 1);
+    assert_eq!(counts.rejected.unknown_operation.get(), 1);
+}
+
+#[test]
+fn callers_are_checked() {
+    let sim = Sim::new();
+    let server = sim.spawn(serve);
+    sim.run(|| {
+        let (rc, _) = idol_runtime::sim::sys_send(server, 3, &[], &mut [], &[]);
+        assert_eq!(rc, u32::from(idol_runtime::ClientError::AccessDenied));
+    });
+    sim.run(|| {
+        let echo = client::Echo::from(server);
+        assert_eq!(echo.reset(), Ok(()));
+    });
+}
+
+#[test]
+fn short_leases_are_rejected() {
+    let sim = Sim::new();
+    let server = sim.spawn(serve);
+    sim.run(|| {
+        let mut reply = [0; server::REVERSE_REPLY_SIZE];
+        let mut dst = [0; 4];
+        let (rc, _) = idol_runtime::sim::sys_send(
+            server,
+            2,
+            &[],
+            &mut reply,
+            &[
+                idol_runtime::sim::Lease::read_only(&[]),
+                idol_runtime::sim::Lease::write_only(&mut dst),
+            ],
+        );
+        assert_eq!(rc, u32::from(idol_runtime::ClientError::BadLeaseLength));
+    });
+}
+
+#[test]
+fn leases_without_access_are_rejected() {
+    let sim = Sim::new();
+    let server = sim.spawn(serve);
+    sim.run(|| {
+        let mut reply = [0; server::REVERSE_REPLY_SIZE];
+        let mut dst = [0; 4];
+        let (rc, _) = idol_runtime::sim::sys_send(
+            server,
+            2,
+            &[],
+            &mut reply,
+            &[
+                idol_runtime::sim::Lease::write_only(&mut [1, 2]),
+                idol_runtime::sim::Lease::write_only(&mut dst),
+            ],
+        );
+        assert_eq!(
+            rc,
+            u32::from(idol_runtime::ClientError::BadLeaseAttributes)
+        );
+    });
+}
+
+#[test]
+fn leases_stream_through_buffers() {
+    let sim = Sim::new();
+    let server = sim.spawn(serve);
+    sim.run(|| {
+        let echo = client::Echo::from(server);
+        assert_eq!(echo.sum(&[]), Ok(0));
+        assert_eq!(echo.sum(&[1, 2, 3, 4, 5, 6, 7]), Ok(28));
+
+        let mut dst = [0; 8];
+        assert_eq!(echo.fill(7, &mut dst), Ok(8));
+        assert_eq!(dst, [7; 8]);
+        assert_eq!(echo.fill(9, &mut dst[..5]), Ok(5));
+        assert_eq!(dst, [9, 9, 9, 9, 9, 7, 7, 7]);
+    });
+}
+
+#[test]
+fn leases_copy_to_leases() {
+    let sim = Sim::new();
+    let server = sim.spawn(serve);
+    sim.run(|| {
+        let echo = client::Echo::from(server);
+        let src: Vec<u8> = (0..200).collect();
+        let mut dst = [0; 210];
+        assert_eq!(echo.copy(10, &src, &mut dst), Ok(()));
+        assert_eq!(&dst[..10], &[0; 10]);
+        assert_eq!(&dst[10..], &src[..]);
+
+        assert_eq!(echo.copy(11, &src, &mut dst), Err(EchoError::TooShort));
+    });
+}
+
+#[test]
+fn str_leases_are_checked() {
+    let sim = Sim::new();
+    let server = sim.spawn(serve);
+    sim.run(|| {
+        let echo = client::Echo::from(server);
+        assert_eq!(echo.count_chars("h\u{e9}llo"), Ok(5));
+
+        let mut reply = [0; server::COUNT_CHARS_REPLY_SIZE];
+        let (rc, _) = idol_runtime::sim::sys_send(
+            server,
+            7,
+            &[],
+            &mut reply,
+            &[idol_runtime::sim::Lease::read_only(&[b'h', 0xC3])],
+        );
+        assert_eq!(rc, u32::from(idol_runtime::ClientError::BadMessage));
+    });
+}
+
+#[test]
+fn bounded_replies_send_only_values_present() {
+    let sim = Sim::new();
+    let server = sim.spawn(serve);
+    sim.run(|| {
+        let echo = client::Echo::from(server);
+        assert_eq!(echo.take(0).unwrap().len(), 0);
+        assert_eq!(echo.take(3).unwrap().as_slice(), &[10, 20, 30]);
+        assert_eq!(echo.take(5), Err(EchoError::TooShort));
+
+        let mut reply = [0; server::TAKE_REPLY_SIZE];
+        let (rc, len) = idol_runtime::sim::sys_send(
+            server,
+            8,
+            &2u32.to_le_bytes(),
+            &mut reply,
+            &[],
+        );
+        assert_eq!((rc, len), (0, 4));
+    });
+}
@@ -0,0 +1,399 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Command-line frontend to the Idol compiler, for use outside of Cargo
+//! builds.
+
+use idol::layout::TypeSizes;
+use idol::syntax::Interface;
+use idol::{ErrorPolicy, Generator, SectionEncoding};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::exit;
+
+const USAGE: &str = "\
+usage: idol <command> [options] <file.idol>
+
+commands:
+    check                       parse and validate an interface
+    client                      generate a client stub
+    server [--style STYLE]      generate server support code (default InOrder)
+    fmt                         print an interface in canonical form
+    layout [--size TYPE=N]...   print the message layout of each operation
+    diff                        compare two versions of an interface, given
+                                as <old.idol> <new.idol>; exits with status 1
+                                if any change is breaking
+    doc                         generate Markdown documentation
+    extract [--export DIR]      print the interfaces embedded in an ELF image,
+                                or write each to DIR/<Name>.idol
+    decode --op OP [--message HEX] [--lease BYTES]... [--rc RC]
+           [--reply HEX] [--size TYPE=N]... [--json]
+                                decode a captured message and/or reply for
+                                operation OP (a name or number)
+    encode --op OP [--arg NAME=VALUE]... [--args JSON] [--size TYPE=N]...
+           [--json]             encode a call to operation OP
+
+options:
+    -o, --output FILE           write to FILE instead of stdout
+
+code generation options (client and server):
+    --module NAME               wrap generated code in `pub mod NAME`
+    --docs                      emit doc comments
+    --error-policy panic|return how generated code reports caller errors
+    --no-section                don't embed the interface in .idolatry
+    --compact-section           embed a compact form of the interface
+    --handshake                 answer or check the fingerprint handshake
+    --reflection                answer the reflection operation (server)
+    --api-trait                 emit an <Iface>Api trait (client)
+    --fuzz-harness              emit a harness for fuzzing decoding (server)
+    --counters                  emit dispatch counters (server)
+    --mock                      emit the trait and a Mock<Iface> (client)
+    --userlib PATH              path to the userlib crate
+    --zerocopy PATH             path to the zerocopy crate
+    --idol-runtime PATH         path to the idol-runtime crate
+";
+
+/// Everything gathered from the command line.
+struct Args {
+    command: String,
+    files: Vec<PathBuf>,
+    output: Option<PathBuf>,
+    export: Option<PathBuf>,
+    gen: Generator,
+    sizes: TypeSizes,
+    capture: Capture,
+}
+
+/// A captured message and reply, as given to `decode`.
+#[derive(Default)]
+struct Capture {
+    op: Option<String>,
+    message: Option<Vec<u8>>,
+    lease_lens: Vec<usize>,
+    rc: Option<u32>,
+    reply: Vec<u8>,
+    json: bool,
+    args: Vec<(String, String)>,
+    args_json: Option<String>,
+}
+
+fn main() {
+    let args = parse_args(std::env::args().skip(1)).unwrap_or_else(|msg| {
+        eprintln!("idol: {}", msg);
+        eprint!("{}", USAGE);
+        exit(2);
+    });
+    match run(&args) {
+        Ok(true) => (),
+        Ok(false) => exit(1),
+        Err(e) => {
+            eprintln!("idol: {}", e);
+            exit(1);
+        }
+    }
+}
+
+fn parse_args(mut argv: impl Iterator<Item = String>) -> Result<Args, String> {
+    let command = argv.next().ok_or("no command given")?;
+    if command == "-h" || command == "--help" {
+        print!("{}", USAGE);
+        exit(0);
+    }
+    let mut args = Args {
+        command,
+        files: vec![],
+        output: None,
+        export: None,
+        gen: Generator::new(),
+        sizes: TypeSizes::new(),
+        capture: Capture::default(),
+    };
+
+    while let Some(arg) = argv.next() {
+        let mut value = || {
+            argv.next()
+                .ok_or_else(|| format!("{} requires a value", arg))
+        };
+        match arg.as_str() {
+            "-o" | "--output" => args.output = Some(value()?.into()),
+            "--export" => args.export = Some(value()?.into()),
+            "--style" => {
+                args.gen = args.gen.server_style(value()?.parse()?);
+            }
+            "--size" => {
+                let spec = value()?;
+                let (ty, n) = spec
+                    .rsplit_once('=')
+                    .and_then(|(ty, n)| Some((ty, n.parse().ok()?)))
+                    .ok_or_else(|| format!("bad --size `{}`", spec))?;
+                args.sizes.insert(ty, n);
+            }
+            "--op" => args.capture.op = Some(value()?),
+            "--message" => args.capture.message = Some(parse_hex(&value()?)?),
+            "--lease" => {
+                let len = value()?;
+                let len = len
+                    .parse()
+                    .map_err(|_| format!("bad lease size `{}`", len))?;
+                args.capture.lease_lens.push(len);
+            }
+            "--rc" => args.capture.rc = Some(parse_number(&value()?)?),
+            "--reply" => args.capture.reply = parse_hex(&value()?)?,
+            "--json" => args.capture.json = true,
+            "--arg" => {
+                let spec = value()?;
+                let (name, v) = spec
+                    .split_once('=')
+                    .ok_or_else(|| format!("bad --arg `{}`", spec))?;
+                args.capture.args.push((name.to_string(), v.to_string()));
+            }
+            "--args" => args.capture.args_json = Some(value()?),
+            "--module" => args.gen = args.gen.module(value()?),
+            "--docs" => args.gen = args.gen.docs(true),
+            "--error-policy" => {
+                let policy = match value()?.as_str() {
+                    "panic" => ErrorPolicy::Panic,
+                    "return" => ErrorPolicy::Return,
+                    other => {
+                        return Err(format!("unknown error policy `{}`", other))
+                    }
+                };
+                args.gen = args.gen.error_policy(policy);
+            }
+            "--no-section" => args.gen = args.gen.emit_section(false),
+            "--handshake" => args.gen = args.gen.handshake(true),
+            "--reflection" => args.gen = args.gen.reflection(true),
+            "--api-trait" => args.gen = args.gen.api_trait(true),
+            "--mock" => args.gen = args.gen.mock(true),
+            "--fuzz-harness" => args.gen = args.gen.fuzz_harness(true),
+            "--counters" => args.gen = args.gen.counters(true),
+            "--compact-section" => {
+                args.gen = args.gen.section_encoding(SectionEncoding::Compact)
+            }
+            "--userlib" => args.gen = args.gen.userlib_path(value()?),
+            "--zerocopy" => args.gen = args.gen.zerocopy_path(value()?),
+            "--idol-runtime" => args.gen = args.gen.idol_runtime_path(value()?),
+            s if s.starts_with('-') && s != "-" => {
+                return Err(format!("unknown option `{}`", s))
+            }
+            _ => args.files.push(arg.into()),
+        }
+    }
+
+    if (args.command == "decode" || args.command == "encode")
+        && args.capture.op.is_none()
+    {
+        return Err(format!("{} requires --op", args.command));
+    }
+    let expected = if args.command == "diff" { 2 } else { 1 };
+    if args.files.len() != expected {
+        return Err(format!(
+            "{} expects {} file argument(s)",
+            args.command, expected
+        ));
+    }
+    Ok(args)
+}
+
+/// Runs the command, returning `Ok(false)` if it completed but found a problem
+/// that should be reflected in the exit status.
+fn run(args: &Args) -> Result<bool, idol::Error> {
+    let mut out = vec![];
+    let mut ok = true;
+
+    // The input to extract is an image rather than an interface, so handle it
+    // before trying to load one.
+    if args.command == "extract" {
+        extract(args, &mut out)?;
+        return finish(args, &out).map(|()| ok);
+    }
+
+    let (iface, text) = Interface::load(&args.files[0])?;
+
+    match args.command.as_str() {
+        "check" => iface.validate()?,
+        "client" => args.gen.generate_client_stub(&iface, &mut out)?,
+        "server" => {
+            args.gen.generate_server_support(&iface, &text, &mut out)?
+        }
+        "fmt" => out.extend_from_slice(iface.to_text().as_bytes()),
+        "layout" => {
+            for l in idol::layout::op_layouts(&iface, &args.sizes) {
+                write!(out, "{}", l)?;
+            }
+        }
+        "diff" => {
+            let (new, _) = Interface::load(&args.files[1])?;
+            for change in idol::diff::diff(&iface, &new) {
+                ok &= !change.is_breaking();
+                writeln!(out, "{}", change)?;
+            }
+        }
+        "doc" => idol::doc::generate_markdown(&iface, &mut out)?,
+        "decode" => decode(args, &iface, &mut out)?,
+        "encode" => encode(args, &iface, &mut out)?,
+        other => {
+            eprintln!("idol: unknown command `{}`", other);
+            eprint!("{}", USAGE);
+            exit(2);
+        }
+    }
+
+    finish(args, &out)?;
+    Ok(ok)
+}
+
+fn extract(args: &Args, mut out: impl Write) -> Result<(), idol::Error> {
+    let found = idol::extract::extract_file(&args.files[0])?;
+    if found.is_empty() {
+        return Err(idol::Error::Extract(format!(
+            "{}: no interface definitions found",
+            args.files[0].display()
+        )));
+    }
+    for embedded in found {
+        match &args.export {
+            Some(dir) => {
+                let path =
+                    dir.join(format!("{}.idol", embedded.interface.name));
+                // Compact definitions have lost their formatting anyway, so
+                // write those out in canonical form instead.
+                let text = match embedded.encoding {
+                    SectionEncoding::Source => embedded.text,
+                    SectionEncoding::Compact => embedded.interface.to_text(),
+                };
+                std::fs::write(&path, text)
+                    .map_err(|source| idol::Error::Io { path, source })?;
+            }
+            None => {
+                writeln!(out, "// {}", embedded.symbol)?;
+                write!(out, "{}", embedded.interface.to_text())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn decode(
+    args: &Args,
+    iface: &Interface,
+    mut out: impl Write,
+) -> Result<(), idol::Error> {
+    use idol::decode;
+
+    let cap = &args.capture;
+    // `parse_args` checked that this is present.
+    let op = cap.op.as_deref().unwrap_or_default();
+    let number = match iface.ops.get_index_of(op) {
+        Some(idx) => idx as u16 + 1,
+        None => parse_number(op)
+            .ok()
+            .and_then(|n| u16::try_from(n).ok())
+            .ok_or_else(|| {
+                idol::Error::Decode(format!(
+                    "{} has no operation `{}`",
+                    iface.name, op
+                ))
+            })?,
+    };
+
+    let message = match &cap.message {
+        Some(payload) => Some(decode::decode_message(
+            iface,
+            &args.sizes,
+            number,
+            payload,
+            &cap.lease_lens,
+        )?),
+        None => None,
+    };
+    let reply = match cap.rc {
+        Some(rc) => Some(decode::decode_reply(
+            iface,
+            &args.sizes,
+            number,
+            rc,
+            &cap.reply,
+        )?),
+        None => None,
+    };
+
+    if cap.json {
+        #[derive(serde::Serialize)]
+        struct Decoded {
+            message: Option<decode::Message>,
+            reply: Option<decode::Reply>,
+        }
+        writeln!(out, "{}", decode::to_json(&Decoded { message, reply }))?;
+    } else {
+        if let Some(m) = message {
+            writeln!(out, "{}", m)?;
+        }
+        if let Some(r) = reply {
+            writeln!(out, "reply: {}", r)?;
+        }
+    }
+    Ok(())
+}
+
+fn encode(
+    args: &Args,
+    iface: &Interface,
+    mut out: impl Write,
+) -> Result<(), idol::Error> {
+    let cap = &args.capture;
+    // `parse_args` checked that this is present.
+    let op = cap.op.as_deref().unwrap_or_default();
+    let call = match &cap.args_json {
+        Some(json) => {
+            let values = serde_json::from_str(json).map_err(|e| {
+                idol::Error::Encode(format!("bad --args: {}", e))
+            })?;
+            idol::encode::encode_call(iface, &args.sizes, op, &values)?
+        }
+        None => {
+            idol::encode::encode_call_str(iface, &args.sizes, op, &cap.args)?
+        }
+    };
+
+    if cap.json {
+        writeln!(out, "{}", idol::decode::to_json(&call))?;
+    } else {
+        write!(out, "{}", call)?;
+    }
+    Ok(())
+}
+
+/// Parses a string of hex digits, optionally separated by spaces, into bytes.
+fn parse_hex(s: &str) -> Result<Vec<u8>, String> {
+    idol::encode::parse_hex(s).ok_or_else(|| format!("bad hex `{}`", s))
+}
+
+/// Parses a decimal or `0x`-prefixed hexadecimal number.
+fn parse_number(s: &str) -> Result<u32, String> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|_| format!("bad number `{}`", s))
+}
+
+/// Writes the output of a command to wherever it was asked to go.
+fn finish(args: &Args, out: &[u8]) -> Result<(), idol::Error> {
+    match &args.output {
+        Some(path) => {
+            std::fs::write(path, out).map_err(|source| idol::Error::Io {
+                path: path.clone(),
+                source,
+            })?
+        }
+        None => std::io::stdout().write_all(out).map_err(|source| {
+            idol::Error::Io {
+                path: "<stdout>".into(),
+                source,
+            }
+        })?,
+    }
+    Ok(())
+}
@@ -2,55 +2,83 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use super::{common, syntax};
-use std::env;
-use std::fs::File;
-use std::path::PathBuf;
-
-pub fn build_client_stub(
-    source: &str,
-    stub_name: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
-    let stub_file = File::create(out.join(stub_name)).unwrap();
-
-    generate_client_stub_from_file(source, stub_file)?;
-    println!("cargo:rerun-if-changed={}", source);
-    Ok(())
+use super::{common, layout, syntax, Error, ErrorPolicy, Generator};
+
+pub fn build_client_stub(source: &str, stub_name: &str) -> Result<(), Error> {
+    Generator::new().build_client_stub(source, stub_name)
 }
 
 pub fn generate_client_stub_from_file(
     source: impl AsRef<std::path::Path>,
     out: impl std::io::Write,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let text = std::fs::read_to_string(source)?;
-    let iface: syntax::Interface = ron::de::from_str(&text)?;
+) -> Result<(), Error> {
+    let (iface, _text) = syntax::Interface::load(source)?;
     generate_client_stub(&iface, out)
 }
 
 pub fn generate_client_stub(
+    iface: &syntax::Interface,
+    out: impl std::io::Write,
+) -> Result<(), Error> {
+    Generator::new().generate_client_stub(iface, out)
+}
+
+pub(crate) fn write_client_stub(
+    gen: &Generator,
     iface: &syntax::Interface,
     mut out: impl std::io::Write,
-) -> Result<(), Box<dyn std::error::Error>> {
-    common::generate_op_enum(iface, &mut out)?;
+) -> Result<(), Error> {
+    common::write_op_enum(gen, iface, &mut out)?;
 
+    if gen.docs {
+        writeln!(
+            out,
+            "/// Client for the `{}` interface, wrapping the `TaskId` of a \
+             server.",
+            iface.name
+        )?;
+    }
     writeln!(out, "#[derive(Clone, Debug)]")?;
     writeln!(out, "pub struct {} {{", iface.name)?;
-    writeln!(out, "    current_id: core::cell::Cell<userlib::TaskId>,")?;
+    writeln!(
+        out,
+        "    current_id: ::core::cell::Cell<{}::TaskId>,",
+        gen.userlib
+    )?;
     writeln!(out, "}}")?;
     writeln!(out)?;
 
-    writeln!(out, "impl From<userlib::TaskId> for {} {{", iface.name)?;
-    writeln!(out, "    fn from(x: userlib::TaskId) -> Self {{")?;
     writeln!(
         out,
-        "        Self {{ current_id: core::cell::Cell::new(x) }}"
+        "impl From<{}::TaskId> for {} {{",
+        gen.userlib, iface.name
+    )?;
+    writeln!(out, "    fn from(x: {}::TaskId) -> Self {{", gen.userlib)?;
+    writeln!(
+        out,
+        "        Self {{ current_id: ::core::cell::Cell::new(x) }}"
     )?;
     writeln!(out, "    }}")?;
     writeln!(out, "}}")?;
     writeln!(out)?;
 
     writeln!(out, "impl {} {{", iface.name)?;
+    if gen.docs {
+        writeln!(
+            out,
+            "    /// Fingerprint of the interface definition this client was \
+             generated from."
+        )?;
+    }
+    writeln!(
+        out,
+        "    pub const FINGERPRINT: u64 = {}Operation::FINGERPRINT;",
+        iface.name
+    )?;
+    writeln!(out)?;
+    if gen.handshake {
+        write_client_check_fingerprint(gen, &mut out)?;
+    }
     for (idx, (name, op)) in iface.ops.iter().enumerate() {
         writeln!(out, "    // operation: {} ({})", name, idx)?;
         if op.idempotent {
@@ -62,51 +90,10 @@ pub fn generate_client_stub(
             )?;
         }
 
-        // Let's do some checks
-
-        if op.idempotent {
-            // An idempotent operation with a read-write lease seems like a
-            // problem, since it could forward half-initialized state from one
-            // instance of the server to the next, potentially propagating the
-            // crash; we could potentially handle it but for now, we'll reject
-            // it.
-            if op.leases.values().any(|lease| lease.read && lease.write) {
-                return Err("idempotent operation with read/write lease".into());
-            }
-        } else {
-            // A non-idempotent operation had better have an error type.
-            match &op.reply {
-                syntax::Reply::Result { .. } => (),
-            }
-        }
-
-        writeln!(out, "    pub fn {}(", name)?;
-        writeln!(out, "        &self,")?;
-        for (name, arg) in &op.args {
-            writeln!(out, "        {}: {},", name, arg.ty.0)?;
-        }
-        for (name, lease) in &op.leases {
-            let reftype = if lease.write {
-                "&mut "
-            } else if lease.read {
-                "&"
-            } else {
-                panic!("lease {} grants no access", name);
-            };
-            writeln!(out, "        {}: {}{},", name, reftype, lease.ty.0)?;
-        }
-        write!(out, "    )")?;
-        match &op.reply {
-            syntax::Reply::Result { ok, err } => {
-                write!(out, " -> Result<{}, ", ok.display())?;
-                match err {
-                    syntax::Error::CLike(ty) => {
-                        write!(out, "{}", ty.0)?;
-                    }
-                }
-                write!(out, ">")?;
-            }
+        if gen.docs {
+            write_client_op_docs(iface, idx, name, op, &mut out)?;
         }
+        write_client_signature(gen, "    ", "pub ", name, op, &mut out)?;
         writeln!(out, " {{")?;
 
         // Map the args with user-chosen names, which are good for rustdoc, into
@@ -130,19 +117,21 @@ pub fn generate_client_stub(
 
         // Perform lease validation.
         for (leasename, lease) in &op.leases {
+            if let Some(n) = lease.min_len {
+                writeln!(out, "        if arg_{}.len() < {} {{", leasename, n)?;
+                write_client_failure(gen, op, "BadLeaseLength", 12, &mut out)?;
+                writeln!(out, "        }}")?;
+            }
             if let Some(n) = lease.max_len {
                 writeln!(out, "        if arg_{}.len() > {} {{", leasename, n)?;
-                // Note: we're not generating a panic message in the client to
-                // save ROM space. If the user chases the line number into the
-                // client stub source file the error should be clear.
-                writeln!(out, "            panic!();")?;
+                write_client_failure(gen, op, "BadLeaseLength", 12, &mut out)?;
                 writeln!(out, "        }}")?;
             }
         }
 
         // Define args struct.
         writeln!(out, "        #[allow(non_camel_case_types)]")?;
-        writeln!(out, "        #[derive(zerocopy::AsBytes)]")?;
+        writeln!(out, "        #[derive({}::AsBytes)]", gen.zerocopy)?;
         writeln!(out, "        #[repr(C, packed)]")?;
         writeln!(out, "        struct {}_{}_ARGS {{", iface.name, name)?;
         for (argname, arg) in &op.args {
@@ -157,8 +146,8 @@ pub fn generate_client_stub(
             syntax::Reply::Result { ok, err } => {
                 writeln!(
                     out,
-                    "            let oksize = core::mem::size_of::<{}>();",
-                    ok.display()
+                    "            let oksize = ::core::mem::size_of::<{}>();",
+                    common::reply_size_ty(ok)
                 )?;
                 match err {
                     syntax::Error::CLike(_ty) => {
@@ -189,10 +178,14 @@ pub fn generate_client_stub(
 
         writeln!(out, "        let task = self.current_id.get();")?;
         writeln!(out)?;
-        writeln!(out, "        let (rc, len) = sys_send(")?;
+        writeln!(out, "        let (rc, len) = {}::sys_send(", gen.userlib)?;
         writeln!(out, "            task,")?;
         writeln!(out, "            {}Operation::{} as u16,", iface.name, name)?;
-        writeln!(out, "            zerocopy::AsBytes::as_bytes(&args),")?;
+        writeln!(
+            out,
+            "            {}::AsBytes::as_bytes(&args),",
+            gen.zerocopy
+        )?;
         writeln!(out, "            &mut reply,")?;
         writeln!(out, "            &[")?;
         for (leasename, lease) in &op.leases {
@@ -200,12 +193,14 @@ pub fn generate_client_stub(
                 (true, true) => "read_write",
                 (false, true) => "write_only",
                 (true, false) => "read_only",
-                (false, false) => panic!("should have been caught above"),
+                (false, false) => unreachable!("should have been caught above"),
             };
+            // Leases are of bytes, so strings need converting.
+            let as_bytes = if lease.ty.is_str() { ".as_bytes()" } else { "" };
             writeln!(
                 out,
-                "                userlib::Lease::{}(arg_{}),",
-                ctor, leasename
+                "                {}::Lease::{}(arg_{}{}),",
+                gen.userlib, ctor, leasename, as_bytes
             )?;
         }
         writeln!(out, "            ],")?;
@@ -215,48 +210,74 @@ pub fn generate_client_stub(
             syntax::Reply::Result { ok, err } => {
                 let reply_ty = format!("{}_{}_REPLY", iface.name, name);
                 writeln!(out, "        if rc == 0 {{")?;
-                writeln!(out, "            #[derive(zerocopy::FromBytes, zerocopy::Unaligned)]")?;
-                writeln!(out, "            #[repr(C, packed)]")?;
-                writeln!(out, "            struct {} {{", reply_ty)?;
-                writeln!(out, "                value: {},", ok.repr_ty().0)?;
-                writeln!(out, "            }}")?;
-                writeln!(out, "            let lv = zerocopy::LayoutVerified::<_, {}>::new_unaligned(&reply[..])", reply_ty)?;
-                writeln!(out, "                .unwrap();")?;
-                writeln!(
-                    out,
-                    "            let v: {} = lv.value;",
-                    ok.repr_ty().0
-                )?;
-                match &ok.recv {
-                    syntax::RecvStrategy::FromBytes => {
-                        writeln!(out, "            Ok(v)")?;
-                    }
-                    syntax::RecvStrategy::From(_, None) => {
-                        writeln!(out, "            Ok(v.into())")?;
-                    }
-                    syntax::RecvStrategy::From(_, Some(f)) => {
-                        writeln!(out, "            Ok({}(v))", f)?;
-                    }
-                    syntax::RecvStrategy::FromPrimitive(p) => {
-                        writeln!(out, "            Ok(<{} as userlib::FromPrimitive>::from_{}(v).unwrap())", ok.ty.0, p.0)?;
+                if ok.ty.bounded().is_some() {
+                    // Bounded replies are as long as the values sent.
+                    let conv = format!(
+                        "<{}>::from_bytes(&reply[..len])",
+                        common::reply_ty(gen, ok)
+                    );
+                    match gen.error_policy {
+                        ErrorPolicy::Panic => {
+                            writeln!(out, "            Ok({}.unwrap())", conv)?;
+                        }
+                        ErrorPolicy::Return => {
+                            writeln!(out, "            {}", conv)?;
+                            writeln!(
+                                out,
+                                "                .ok_or_else(|| {})",
+                                client_failure(gen, op, "BadMessage")
+                            )?;
+                        }
                     }
+                } else {
+                    write_client_reply_value(gen, op, ok, &reply_ty, &mut out)?;
                 }
                 writeln!(out, "        }} else {{")?;
                 match err {
                     syntax::Error::CLike(ty) => {
-                        writeln!(out, "            assert!(len == 0);")?;
+                        match gen.error_policy {
+                            ErrorPolicy::Panic => {
+                                writeln!(
+                                    out,
+                                    "            assert!(len == 0);"
+                                )?;
+                            }
+                            ErrorPolicy::Return => {
+                                writeln!(out, "            if len != 0 {{")?;
+                                write_client_failure(
+                                    gen,
+                                    op,
+                                    "BadMessage",
+                                    16,
+                                    &mut out,
+                                )?;
+                                writeln!(out, "            }}")?;
+                            }
+                        }
                         writeln!(
                             out,
-                            "            if let Some(g) = userlib::extract_new_generation(rc) {{"
+                            "            if let Some(g) = {}::extract_new_generation(rc) {{",
+                            gen.userlib
                         )?;
-                        writeln!(out, "                self.current_id.set(userlib::TaskId::for_index_and_gen(task.index(), g));")?;
+                        writeln!(out, "                self.current_id.set({}::TaskId::for_index_and_gen(task.index(), g));", gen.userlib)?;
                         writeln!(out, "            }}")?;
                         writeln!(
                             out,
-                            "            Err(<{} as core::convert::TryFrom<u32>>::try_from(rc)",
+                            "            Err(<{} as ::core::convert::TryFrom<u32>>::try_from(rc)",
                             ty.0
                         )?;
-                        writeln!(out, "                .unwrap())")?;
+                        match gen.error_policy {
+                            ErrorPolicy::Panic => {
+                                writeln!(out, "                .unwrap())")?;
+                            }
+                            ErrorPolicy::Return => {
+                                writeln!(
+                                    out,
+                                    "                .unwrap_or_else(|_| {}))",
+                                    client_failure(gen, op, "BadMessage")
+                                )?;
+                            }
+                        }
                     }
                 }
                 writeln!(out, "        }}")?;
@@ -269,5 +290,556 @@ pub fn generate_client_stub(
     }
     writeln!(out, "}}")?;
     writeln!(out)?;
+
+    if gen.api_trait {
+        write_client_api_trait(gen, iface, &mut out)?;
+    }
+    if gen.mock {
+        write_client_mock(gen, iface, &mut out)?;
+    }
+    Ok(())
+}
+
+/// Returns the parameters of the client method for `op`, as name/type pairs.
+fn client_params(op: &syntax::Operation) -> Vec<(&str, String)> {
+    let args = op
+        .args
+        .iter()
+        .map(|(name, arg)| (name.as_str(), arg.ty.0.clone()));
+    let leases = op.leases.iter().map(|(name, lease)| {
+        let reftype = if lease.write {
+            "&mut "
+        } else if lease.read {
+            "&"
+        } else {
+            unreachable!("lease {} grants no access", name);
+        };
+        (name.as_str(), format!("{}{}", reftype, lease.ty.0))
+    });
+    args.chain(leases).collect()
+}
+
+/// Returns the return type of the client method for `op`.
+fn client_return_ty(gen: &Generator, op: &syntax::Operation) -> String {
+    match &op.reply {
+        syntax::Reply::Result { ok, err } => match err {
+            syntax::Error::CLike(ty) => {
+                format!("Result<{}, {}>", common::reply_ty(gen, ok), ty.0)
+            }
+        },
+    }
+}
+
+/// Writes the decoding of the successful reply `ok`, which is of fixed size,
+/// from `reply`, as the value of the `if rc == 0` block.
+fn write_client_reply_value(
+    gen: &Generator,
+    op: &syntax::Operation,
+    ok: &syntax::AttributedTy,
+    reply_ty: &str,
+    mut out: impl std::io::Write,
+) -> Result<(), Error> {
+    writeln!(
+        out,
+        "            #[derive({0}::FromBytes, {0}::Unaligned)]",
+        gen.zerocopy
+    )?;
+    writeln!(out, "            #[repr(C, packed)]")?;
+    writeln!(out, "            struct {} {{", reply_ty)?;
+    writeln!(out, "                value: {},", ok.repr_ty().0)?;
+    writeln!(out, "            }}")?;
+    writeln!(out, "            let lv = {}::LayoutVerified::<_, {}>::new_unaligned(&reply[..])", gen.zerocopy, reply_ty)?;
+    writeln!(out, "                .unwrap();")?;
+    writeln!(out, "            let v: {} = lv.value;", ok.repr_ty().0)?;
+    match &ok.recv {
+        syntax::RecvStrategy::FromBytes => {
+            writeln!(out, "            Ok(v)")?;
+        }
+        syntax::RecvStrategy::From(_, None) => {
+            writeln!(out, "            Ok(v.into())")?;
+        }
+        syntax::RecvStrategy::From(_, Some(f)) => {
+            writeln!(out, "            Ok({}(v))", f)?;
+        }
+        syntax::RecvStrategy::FromPrimitive(p) => {
+            let conv = format!(
+                "<{} as {}::FromPrimitive>::from_{}(v)",
+                ok.ty.0, gen.userlib, p.0
+            );
+            match gen.error_policy {
+                ErrorPolicy::Panic => {
+                    writeln!(out, "            Ok({}.unwrap())", conv)?;
+                }
+                ErrorPolicy::Return => {
+                    writeln!(out, "            {}", conv)?;
+                    writeln!(
+                        out,
+                        "                .ok_or_else(|| {})",
+                        client_failure(gen, op, "BadMessage")
+                    )?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes the signature of the client method for `op`, up to but not
+/// including the body or semicolon, with `vis` in front. Lines are indented
+/// by `indent`, or by a further four spaces for parameters.
+fn write_client_signature(
+    gen: &Generator,
+    indent: &str,
+    vis: &str,
+    name: &str,
+    op: &syntax::Operation,
+    mut out: impl std::io::Write,
+) -> Result<(), Error> {
+    writeln!(out, "{}{}fn {}(", indent, vis, name)?;
+    writeln!(out, "{}    &self,", indent)?;
+    for (name, ty) in client_params(op) {
+        writeln!(out, "{}    {}: {},", indent, name, ty)?;
+    }
+    write!(out, "{}) -> {}", indent, client_return_ty(gen, op))?;
+    Ok(())
+}
+
+/// Writes the `<Iface>Api` trait and its implementation for the client.
+fn write_client_api_trait(
+    gen: &Generator,
+    iface: &syntax::Interface,
+    mut out: impl std::io::Write,
+) -> Result<(), Error> {
+    if gen.docs {
+        writeln!(
+            out,
+            "/// Operations of the `{}` interface, implemented by the client and",
+            iface.name
+        )?;
+        writeln!(out, "/// by any stand-ins for it.")?;
+    }
+    writeln!(out, "pub trait {}Api {{", iface.name)?;
+    for (idx, (name, op)) in iface.ops.iter().enumerate() {
+        if gen.docs {
+            write_client_op_docs(iface, idx, name, op, &mut out)?;
+        }
+        write_client_signature(gen, "    ", "", name, op, &mut out)?;
+        writeln!(out, ";")?;
+    }
+    writeln!(out, "}}")?;
+    writeln!(out)?;
+
+    writeln!(out, "impl {0}Api for {0} {{", iface.name)?;
+    for (name, op) in &iface.ops {
+        write_client_signature(gen, "    ", "", name, op, &mut out)?;
+        writeln!(out, " {{")?;
+        let params = client_params(op)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        writeln!(
+            out,
+            "        {}::{}(self, {})",
+            iface.name,
+            name,
+            params.join(", ")
+        )?;
+        writeln!(out, "    }}")?;
+    }
+    writeln!(out, "}}")?;
+    writeln!(out)?;
+    Ok(())
+}
+
+/// Writes `Mock<Iface>`, along with a struct per operation recording calls to
+/// it. These live in a module of their own, which links `std` for them.
+fn write_client_mock(
+    gen: &Generator,
+    iface: &syntax::Interface,
+    mut out: impl std::io::Write,
+) -> Result<(), Error> {
+    let module = format!("{}_mock", snake_case(&iface.name));
+    let mock = format!("Mock{}", iface.name);
+    let call_ty = |name: &str| format!("{}_{}_CALL", iface.name, name);
+    let expectation_ty =
+        |name: &str| format!("{}_{}_EXPECTATION", iface.name, name);
+
+    // The mock goes unused in builds that only use the real client.
+    writeln!(out, "#[allow(unused_imports)]")?;
+    writeln!(out, "pub use self::{}::*;", module)?;
+    writeln!(out)?;
+    writeln!(out, "pub mod {} {{", module)?;
+    writeln!(out, "    extern crate std;")?;
+    writeln!(out, "    #[allow(unused_imports)]")?;
+    writeln!(out, "    use super::*;")?;
+    writeln!(out, "    use std::boxed::Box;")?;
+    writeln!(out, "    use std::collections::VecDeque;")?;
+    writeln!(out, "    use std::vec::Vec;")?;
+    writeln!(out, "    use ::core::cell::RefCell;")?;
+    writeln!(out)?;
+
+    for (name, op) in &iface.ops {
+        writeln!(
+            out,
+            "    /// A call to `{}` received by `{}`. Leases that the server \
+             could read",
+            name, mock
+        )?;
+        writeln!(
+            out,
+            "    /// are recorded as a copy of their contents on entry."
+        )?;
+        writeln!(out, "    #[allow(non_camel_case_types)]")?;
+        writeln!(out, "    pub struct {} {{", call_ty(name))?;
+        for (argname, arg) in &op.args {
+            writeln!(out, "        pub {}: {},", argname, arg.ty.0)?;
+        }
+        for (leasename, lease) in &op.leases {
+            if lease.read {
+                writeln!(out, "        pub {}: Vec<u8>,", leasename)?;
+            }
+        }
+        writeln!(out, "    }}")?;
+        writeln!(out)?;
+
+        let params = client_params(op)
+            .into_iter()
+            .map(|(_, ty)| ty)
+            .collect::<Vec<_>>();
+        writeln!(out, "    #[allow(non_camel_case_types)]")?;
+        writeln!(
+            out,
+            "    type {} = Box<dyn FnOnce({}) -> {}>;",
+            expectation_ty(name),
+            params.join(", "),
+            client_return_ty(gen, op)
+        )?;
+        writeln!(out)?;
+    }
+
+    writeln!(
+        out,
+        "    /// Stand-in for `{}` that answers each call with the next \
+         expectation",
+        iface.name
+    )?;
+    writeln!(
+        out,
+        "    /// queued for its operation, and records the calls it \
+         receives. Calls with"
+    )?;
+    writeln!(out, "    /// nothing queued panic.")?;
+    writeln!(out, "    #[derive(Default)]")?;
+    writeln!(out, "    pub struct {} {{", mock)?;
+    for name in iface.ops.keys() {
+        writeln!(
+            out,
+            "        {}_expected: RefCell<VecDeque<{}>>,",
+            name,
+            expectation_ty(name)
+        )?;
+        writeln!(
+            out,
+            "        {}_calls: RefCell<Vec<{}>>,",
+            name,
+            call_ty(name)
+        )?;
+    }
+    writeln!(out, "    }}")?;
+    writeln!(out)?;
+
+    writeln!(out, "    impl {} {{", mock)?;
+    writeln!(out, "        pub fn new() -> Self {{")?;
+    writeln!(out, "            Self::default()")?;
+    writeln!(out, "        }}")?;
+    writeln!(out)?;
+    for (name, op) in &iface.ops {
+        let params = client_params(op)
+            .into_iter()
+            .map(|(_, ty)| ty)
+            .collect::<Vec<_>>();
+        writeln!(
+            out,
+            "        /// Queues `f` to answer a call to `{}`, after any \
+             already queued.",
+            name
+        )?;
+        writeln!(out, "        pub fn expect_{}(", name)?;
+        writeln!(out, "            &self,")?;
+        writeln!(
+            out,
+            "            f: impl FnOnce({}) -> {} + 'static,",
+            params.join(", "),
+            client_return_ty(gen, op)
+        )?;
+        writeln!(out, "        ) -> &Self {{")?;
+        writeln!(
+            out,
+            "            self.{}_expected.borrow_mut().push_back(Box::new(f));",
+            name
+        )?;
+        writeln!(out, "            self")?;
+        writeln!(out, "        }}")?;
+        writeln!(out)?;
+        writeln!(
+            out,
+            "        /// Returns the calls to `{}` received so far, oldest \
+             first, and",
+            name
+        )?;
+        writeln!(out, "        /// forgets them.")?;
+        writeln!(
+            out,
+            "        pub fn take_{}_calls(&self) -> Vec<{}> {{",
+            name,
+            call_ty(name)
+        )?;
+        writeln!(
+            out,
+            "            ::core::mem::take(&mut *self.{}_calls.borrow_mut())",
+            name
+        )?;
+        writeln!(out, "        }}")?;
+        writeln!(out)?;
+    }
+    writeln!(
+        out,
+        "        /// Panics if any queued expectation hasn't been used."
+    )?;
+    writeln!(out, "        pub fn checkpoint(&self) {{")?;
+    for name in iface.ops.keys() {
+        writeln!(
+            out,
+            "            let left = self.{}_expected.borrow().len();",
+            name
+        )?;
+        writeln!(
+            out,
+            "            assert!(left == 0, \"{}: {{}} expected call(s) to {} not made\", left);",
+            mock, name
+        )?;
+    }
+    writeln!(out, "        }}")?;
+    writeln!(out, "    }}")?;
+    writeln!(out)?;
+
+    writeln!(out, "    #[allow(clippy::clone_on_copy)]")?;
+    writeln!(out, "    impl super::{}Api for {} {{", iface.name, mock)?;
+    for (name, op) in &iface.ops {
+        write_client_signature(gen, "        ", "", name, op, &mut out)?;
+        writeln!(out, " {{")?;
+        writeln!(
+            out,
+            "            self.{}_calls.borrow_mut().push({} {{",
+            name,
+            call_ty(name)
+        )?;
+        for argname in op.args.keys() {
+            writeln!(out, "                {0}: {0}.clone(),", argname)?;
+        }
+        for (leasename, lease) in &op.leases {
+            // Read-write leases arrive as `&mut`, which needs reborrowing.
+            let reborrow = match (lease.read, lease.write) {
+                (true, true) => "&*",
+                (true, false) => "",
+                (false, _) => continue,
+            };
+            writeln!(
+                out,
+                "                {}: {}::AsBytes::as_bytes({}{}).to_vec(),",
+                leasename, gen.zerocopy, reborrow, leasename
+            )?;
+        }
+        writeln!(out, "            }});")?;
+        writeln!(
+            out,
+            "            let f = self.{}_expected.borrow_mut().pop_front()",
+            name
+        )?;
+        writeln!(
+            out,
+            "                .expect(\"unexpected call to {}::{}\");",
+            mock, name
+        )?;
+        let params = client_params(op)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        writeln!(out, "            f({})", params.join(", "))?;
+        writeln!(out, "        }}")?;
+    }
+    writeln!(out, "    }}")?;
+    writeln!(out, "}}")?;
+    writeln!(out)?;
+    Ok(())
+}
+
+/// Converts a CamelCase interface name to snake_case, for module names.
+fn snake_case(name: &str) -> String {
+    let mut out = String::new();
+    for (i, c) in name.chars().enumerate() {
+        if c.is_uppercase() {
+            if i != 0 {
+                out.push('_');
+            }
+            out.extend(c.to_lowercase());
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Writes the method that asks the server for its fingerprint.
+fn write_client_check_fingerprint(
+    gen: &Generator,
+    mut out: impl std::io::Write,
+) -> Result<(), Error> {
+    // This doc comment is emitted regardless of `gen.docs`, because the
+    // method's behavior isn't obvious from its signature.
+    writeln!(
+        out,
+        "    /// Asks the server for the fingerprint of the interface it \
+         implements,"
+    )?;
+    writeln!(
+        out,
+        "    /// returning `true` if it matches `Self::FINGERPRINT`. Servers \
+         that"
+    )?;
+    writeln!(
+        out,
+        "    /// don't take part in the handshake, or that restarted during \
+         the call,"
+    )?;
+    writeln!(out, "    /// produce `false`.")?;
+    writeln!(out, "    pub fn check_fingerprint(&self) -> bool {{")?;
+    writeln!(out, "        let mut reply = [0u8; 8];")?;
+    writeln!(out, "        let task = self.current_id.get();")?;
+    writeln!(
+        out,
+        "        let (rc, len) = {}::sys_send(task, {:#x}, &[], &mut reply, &[]);",
+        gen.userlib,
+        syntax::FINGERPRINT_OP
+    )?;
+    writeln!(
+        out,
+        "        if let Some(g) = {}::extract_new_generation(rc) {{",
+        gen.userlib
+    )?;
+    writeln!(out, "            self.current_id.set({}::TaskId::for_index_and_gen(task.index(), g));", gen.userlib)?;
+    writeln!(out, "        }}")?;
+    writeln!(
+        out,
+        "        rc == 0 && len == reply.len() && u64::from_le_bytes(reply) == Self::FINGERPRINT"
+    )?;
+    writeln!(out, "    }}")?;
+    writeln!(out)?;
+    Ok(())
+}
+
+/// Writes the rustdoc comment for the client method implementing `op`.
+fn write_client_op_docs(
+    iface: &syntax::Interface,
+    idx: usize,
+    name: &str,
+    op: &syntax::Operation,
+    mut out: impl std::io::Write,
+) -> Result<(), Error> {
+    writeln!(
+        out,
+        "    /// Sends `{}.{}` (operation {}) to the server.",
+        iface.name,
+        name,
+        idx + 1
+    )?;
+    if op.args.values().any(|arg| arg.is_constrained()) {
+        writeln!(out, "    ///")?;
+        writeln!(out, "    /// The server rejects calls unless:")?;
+        for (argname, arg) in &op.args {
+            if let Some(c) = common::describe_constraints(arg) {
+                writeln!(out, "    /// - `{}` is {}", argname, c)?;
+            }
+        }
+    }
+    if !op.leases.is_empty() {
+        writeln!(out, "    ///")?;
+        writeln!(out, "    /// Leases:")?;
+        for (leasename, lease) in &op.leases {
+            write!(
+                out,
+                "    /// - `{}`: {}",
+                leasename,
+                layout::access_name(lease.read, lease.write)
+            )?;
+            let unit = if lease.ty.is_str() {
+                "bytes"
+            } else {
+                "elements"
+            };
+            if let Some(n) = lease.min_len {
+                write!(out, ", at least {} {}", n, unit)?;
+            }
+            if let Some(n) = lease.max_len {
+                write!(out, ", at most {} {}", n, unit)?;
+            }
+            writeln!(out)?;
+        }
+    }
+    if op.idempotent {
+        writeln!(out, "    ///")?;
+        writeln!(out, "    /// This operation is idempotent.")?;
+    }
+    if let Some(callers) = common::describe_callers(op) {
+        writeln!(out, "    ///")?;
+        writeln!(out, "    /// {}", callers)?;
+    }
+    Ok(())
+}
+
+/// Returns an expression producing the error for a locally detected failure
+/// in `op`, under `ErrorPolicy::Return`. `code` names a variant of
+/// `ClientError`.
+fn client_failure(
+    gen: &Generator,
+    op: &syntax::Operation,
+    code: &str,
+) -> String {
+    match &op.reply {
+        syntax::Reply::Result { err, .. } => match err {
+            syntax::Error::CLike(ty) => format!(
+                "<{0} as ::core::convert::From<{1}::ClientError>>::from({1}::ClientError::{2})",
+                ty.0, gen.idol_runtime, code
+            ),
+        },
+    }
+}
+
+/// Writes the body of a failed client-side check, according to the error
+/// policy. `code` names the `ClientError` variant that describes the failure.
+fn write_client_failure(
+    gen: &Generator,
+    op: &syntax::Operation,
+    code: &str,
+    indent: usize,
+    mut out: impl std::io::Write,
+) -> Result<(), Error> {
+    match gen.error_policy {
+        ErrorPolicy::Panic => {
+            // Note: we're not generating a panic message in the client to
+            // save ROM space. If the user chases the line number into the
+            // client stub source file the error should be clear.
+            writeln!(out, "{:1$}panic!();", "", indent)?;
+        }
+        ErrorPolicy::Return => {
+            writeln!(
+                out,
+                "{:2$}return Err({});",
+                "",
+                client_failure(gen, op, code),
+                indent
+            )?;
+        }
+    }
     Ok(())
 }
@@ -2,16 +2,32 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use super::syntax;
+use super::{syntax, Error, Generator};
 
 pub fn generate_op_enum(
+    iface: &syntax::Interface,
+    out: impl std::io::Write,
+) -> Result<(), Error> {
+    write_op_enum(&Generator::new(), iface, out)
+}
+
+pub(crate) fn write_op_enum(
+    gen: &Generator,
     iface: &syntax::Interface,
     mut out: impl std::io::Write,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<(), Error> {
+    if gen.docs {
+        writeln!(
+            out,
+            "/// Operations in the `{}` interface, numbered as they are sent.",
+            iface.name
+        )?;
+    }
     writeln!(out, "#[allow(non_camel_case_types)]")?;
     writeln!(
         out,
-        "#[derive(Copy, Clone, Debug, Eq, PartialEq, userlib::FromPrimitive)]"
+        "#[derive(Copy, Clone, Debug, Eq, PartialEq, {}::FromPrimitive)]",
+        gen.userlib
     )?;
     writeln!(out, "pub enum {}Operation {{", iface.name)?;
     for (idx, name) in iface.ops.keys().enumerate() {
@@ -20,5 +36,90 @@ pub fn generate_op_enum(
     writeln!(out, "}}")?;
     writeln!(out)?;
 
+    writeln!(out, "impl {}Operation {{", iface.name)?;
+    if gen.docs {
+        writeln!(
+            out,
+            "    /// Fingerprint of the `{}` interface definition.",
+            iface.name
+        )?;
+    }
+    writeln!(
+        out,
+        "    pub const FINGERPRINT: u64 = {:#018x};",
+        iface.fingerprint()
+    )?;
+    writeln!(out, "}}")?;
+    writeln!(out)?;
+
     Ok(())
 }
+
+/// Describes the restrictions on which tasks may call `op`, as a sentence for
+/// documentation, or returns `None` if there aren't any.
+pub(crate) fn describe_callers(op: &syntax::Operation) -> Option<String> {
+    let list = |names: &[String]| {
+        names
+            .iter()
+            .map(|n| format!("`{}`", n))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    if !op.allowed_callers.is_empty() {
+        Some(format!(
+            "Only these tasks may call this operation: {}.",
+            list(&op.allowed_callers)
+        ))
+    } else if !op.deny_callers.is_empty() {
+        Some(format!(
+            "These tasks may not call this operation: {}.",
+            list(&op.deny_callers)
+        ))
+    } else {
+        None
+    }
+}
+
+/// Describes the constraints on the values of argument `arg`, for
+/// documentation, or returns `None` if there aren't any.
+pub(crate) fn describe_constraints(
+    arg: &syntax::AttributedTy,
+) -> Option<String> {
+    let mut parts = vec![];
+    if let Some((lo, hi)) = arg.range {
+        parts.push(format!("from {} to {}", lo, hi));
+    }
+    if !arg.one_of.is_empty() {
+        let values =
+            arg.one_of.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        parts.push(format!("one of {}", values.join(", ")));
+    }
+    if let Some(f) = &arg.validate {
+        parts.push(format!("accepted by `{}`", f));
+    }
+    if parts.is_empty() {
+        None
+    } else {
+        Some(parts.join(", and "))
+    }
+}
+
+/// Returns the Rust type that server implementations return, and clients
+/// receive, for the reply type `ok`. This is just `ok`, except for bounded
+/// types, which become `BoundedVec`s.
+pub(crate) fn reply_ty(gen: &Generator, ok: &syntax::AttributedTy) -> String {
+    match ok.ty.bounded() {
+        Some((elt, n)) => {
+            format!("{}::BoundedVec<{}, {}>", gen.idol_runtime, elt, n)
+        }
+        None => ok.display().to_string(),
+    }
+}
+
+/// Returns a type the size of the largest reply that `ok` can produce.
+pub(crate) fn reply_size_ty(ok: &syntax::AttributedTy) -> String {
+    match ok.ty.bounded() {
+        Some((elt, n)) => format!("[{}; {}]", elt, n),
+        None => ok.display().to_string(),
+    }
+}
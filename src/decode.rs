@@ -0,0 +1,383 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Host-side decoding of captured IPC traffic.
+//!
+//! Given an interface and the raw pieces of a message (operation number,
+//! argument bytes, lease sizes) or of its reply (response code, reply bytes),
+//! this module recovers the named values, using the layouts computed by
+//! `layout`. Primitives and arrays of them are decoded into `Value`s; other
+//! types, whose sizes come from a `TypeSizes`, are left as raw bytes.
+//!
+//! The results implement `Display` for people, and `Serialize`, for tools; use
+//! `to_json` for the latter.
+
+use super::layout::{self, OpLayout, TypeSizes};
+use super::{syntax, Error};
+use serde::Serialize;
+use std::fmt;
+
+/// Response codes that the Idol runtime uses to reject messages, from
+/// `idol_runtime::ClientError`.
+const CLIENT_ERRORS: [(u32, &str); 7] = [
+    (0xFFFF_FE00, "UnknownOperation"),
+    (0xFFFF_FE01, "BadMessage"),
+    (0xFFFF_FE02, "BadLease"),
+    (0xFFFF_FE03, "WentAway"),
+    (0xFFFF_FE04, "AccessDenied"),
+    (0xFFFF_FE05, "BadLeaseAttributes"),
+    (0xFFFF_FE06, "BadLeaseLength"),
+];
+
+/// Response codes with these bits set mean that the server restarted, with its
+/// new generation in the low byte.
+const DEAD_CODE: u32 = 0xFFFF_FF00;
+
+/// A value decoded from a message.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum Value {
+    Unit,
+    Bool(bool),
+    Char(char),
+    Unsigned(u128),
+    Signed(i128),
+    Float(f64),
+    Array(Vec<Value>),
+    /// Contents of a value whose type we know the size of, but not the
+    /// structure.
+    Bytes(Vec<u8>),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unit => f.write_str("()"),
+            Self::Bool(b) => write!(f, "{}", b),
+            Self::Char(c) => write!(f, "{:?}", c),
+            Self::Unsigned(n) => write!(f, "{}", n),
+            Self::Signed(n) => write!(f, "{}", n),
+            Self::Float(n) => write!(f, "{}", n),
+            Self::Array(elts) => {
+                f.write_str("[")?;
+                for (i, e) in elts.iter().enumerate() {
+                    if i != 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{}", e)?;
+                }
+                f.write_str("]")
+            }
+            Self::Bytes(bytes) => {
+                f.write_str("<")?;
+                for (i, b) in bytes.iter().enumerate() {
+                    if i != 0 {
+                        f.write_str(" ")?;
+                    }
+                    write!(f, "{:02x}", b)?;
+                }
+                f.write_str(">")
+            }
+        }
+    }
+}
+
+/// An argument decoded from a message.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Field {
+    pub name: String,
+    /// Type as it appears in the message; see `layout::FieldLayout::ty`.
+    pub ty: String,
+    pub offset: usize,
+    pub value: Value,
+}
+
+/// A lease sent with a message.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Lease {
+    pub name: String,
+    /// The leased type. For slices whose length is known, this includes the
+    /// length, as an array type.
+    pub ty: String,
+    pub read: bool,
+    pub write: bool,
+    /// Size of the lease in bytes, if known.
+    pub len: Option<usize>,
+}
+
+/// A decoded message.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Message {
+    pub op: String,
+    pub number: u16,
+    pub args: Vec<Field>,
+    pub leases: Vec<Lease>,
+}
+
+/// A decoded reply.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub enum Reply {
+    /// The operation succeeded, returning this value.
+    Ok(Value),
+    /// The server returned an error code of the operation's error type.
+    Err { ty: String, code: u32 },
+    /// The runtime rejected the message, with the named `ClientError`.
+    Rejected(&'static str),
+    /// The server restarted, and now has this generation.
+    Restarted(u8),
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {{", self.op)?;
+        for (i, arg) in self.args.iter().enumerate() {
+            let sep = if i == 0 { " " } else { ", " };
+            write!(f, "{}{}: {}", sep, arg.name, arg.value)?;
+        }
+        if self.args.is_empty() {
+            f.write_str("}")?;
+        } else {
+            f.write_str(" }")?;
+        }
+        for lease in &self.leases {
+            let access = layout::access_name(lease.read, lease.write);
+            write!(f, "\n    {}: {} {}", lease.name, access, lease.ty)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ok(v) => write!(f, "Ok({})", v),
+            Self::Err { ty, code } => write!(f, "Err({}({}))", ty, code),
+            Self::Rejected(e) => write!(f, "rejected: {}", e),
+            Self::Restarted(g) => {
+                write!(f, "server restarted (generation {})", g)
+            }
+        }
+    }
+}
+
+/// Formats a decoded message or reply as JSON.
+pub fn to_json(value: &impl Serialize) -> String {
+    // Nothing we produce has non-string map keys, or anything else JSON
+    // can't represent.
+    serde_json::to_string_pretty(value).expect("decoded values serialize")
+}
+
+/// Decodes a message for operation `number` of `iface`, with arguments
+/// `payload`. `lease_lens` gives the size of each lease in bytes, and may be
+/// empty if they weren't captured.
+///
+/// `sizes` supplies the sizes of any application-defined argument types.
+pub fn decode_message(
+    iface: &syntax::Interface,
+    sizes: &TypeSizes,
+    number: u16,
+    payload: &[u8],
+    lease_lens: &[usize],
+) -> Result<Message, Error> {
+    let op = find_op(iface, sizes, number)?;
+
+    let msg_size = op.msg_size.ok_or_else(|| {
+        Error::Decode(format!(
+            "{}: sizes of some argument types are unknown",
+            op.name
+        ))
+    })?;
+    if payload.len() != msg_size {
+        return Err(Error::Decode(format!(
+            "{}: message is {} bytes, expected {}",
+            op.name,
+            payload.len(),
+            msg_size
+        )));
+    }
+    if !lease_lens.is_empty() && lease_lens.len() != op.leases.len() {
+        return Err(Error::Decode(format!(
+            "{}: {} leases sent, expected {}",
+            op.name,
+            lease_lens.len(),
+            op.leases.len()
+        )));
+    }
+
+    let mut args = vec![];
+    for arg in &op.args {
+        // Both are known, because the message size is.
+        let offset = arg.offset.unwrap_or_default();
+        let size = arg.size.unwrap_or_default();
+        let value = decode_value(&arg.ty, &payload[offset..offset + size])
+            .map_err(|e| {
+                Error::Decode(format!("{}.{}: {}", op.name, arg.name, e))
+            })?;
+        args.push(Field {
+            name: arg.name.clone(),
+            ty: arg.ty.clone(),
+            offset,
+            value,
+        });
+    }
+
+    let leases = op
+        .leases
+        .iter()
+        .enumerate()
+        .map(|(i, lease)| {
+            let len = lease_lens.get(i).copied();
+            let ty = match (
+                layout::slice_element(&lease.ty),
+                len,
+                lease.element_size,
+            ) {
+                (Some(elt), Some(len), Some(size))
+                    if len.checked_rem(size) == Some(0) =>
+                {
+                    format!("[{}; {}]", elt, len / size)
+                }
+                _ => lease.ty.clone(),
+            };
+            Lease {
+                name: lease.name.clone(),
+                ty,
+                read: lease.read,
+                write: lease.write,
+                len,
+            }
+        })
+        .collect();
+
+    Ok(Message {
+        op: op.name,
+        number,
+        args,
+        leases,
+    })
+}
+
+/// Decodes the reply to operation `number` of `iface`, given the response code
+/// `rc` and the reply message `reply`.
+pub fn decode_reply(
+    iface: &syntax::Interface,
+    sizes: &TypeSizes,
+    number: u16,
+    rc: u32,
+    reply: &[u8],
+) -> Result<Reply, Error> {
+    let op = find_op(iface, sizes, number)?;
+
+    if rc & DEAD_CODE == DEAD_CODE {
+        return Ok(Reply::Restarted(rc as u8));
+    }
+    if let Some((_, name)) = CLIENT_ERRORS.iter().find(|(c, _)| *c == rc) {
+        return Ok(Reply::Rejected(name));
+    }
+    if rc != 0 {
+        let syntax::Reply::Result { err, .. } =
+            &iface.ops[op.name.as_str()].reply;
+        let syntax::Error::CLike(ty) = err;
+        return Ok(Reply::Err {
+            ty: ty.0.clone(),
+            code: rc,
+        });
+    }
+
+    let size = op.reply.size.ok_or_else(|| {
+        Error::Decode(format!(
+            "{}: size of reply type {} is unknown",
+            op.name, op.reply.ty
+        ))
+    })?;
+    // Bounded replies can be any whole number of elements up to the maximum.
+    let ty = match layout::parse_bounded(&op.reply.ty) {
+        Some((elt, n)) if size > 0 && reply.len() <= size => {
+            let count = reply.len() * n / size;
+            if count * size != reply.len() * n {
+                return Err(Error::Decode(format!(
+                    "{}: reply is {} bytes, not a whole number of {}",
+                    op.name,
+                    reply.len(),
+                    elt
+                )));
+            }
+            format!("[{}; {}]", elt, count)
+        }
+        _ if reply.len() != size => {
+            return Err(Error::Decode(format!(
+                "{}: reply is {} bytes, expected {}",
+                op.name,
+                reply.len(),
+                size
+            )));
+        }
+        _ => op.reply.ty.clone(),
+    };
+    let value = decode_value(&ty, reply)
+        .map_err(|e| Error::Decode(format!("{} reply: {}", op.name, e)))?;
+    Ok(Reply::Ok(value))
+}
+
+/// Finds the layout of operation `number`.
+fn find_op(
+    iface: &syntax::Interface,
+    sizes: &TypeSizes,
+    number: u16,
+) -> Result<OpLayout, Error> {
+    layout::op_layouts(iface, sizes)
+        .into_iter()
+        .find(|op| op.number == number)
+        .ok_or_else(|| {
+            Error::Decode(format!("{} has no operation {}", iface.name, number))
+        })
+}
+
+/// Decodes `bytes`, which are exactly the size of `ty`.
+fn decode_value(ty: &str, bytes: &[u8]) -> Result<Value, String> {
+    let ty = ty.trim();
+    if let Some((elt, len)) = layout::parse_array(ty) {
+        if len == 0 {
+            return Ok(Value::Array(vec![]));
+        }
+        let size = bytes.len() / len;
+        return bytes
+            .chunks(size.max(1))
+            .take(len)
+            .map(|chunk| decode_value(elt, chunk))
+            .collect::<Result<_, _>>()
+            .map(Value::Array);
+    }
+
+    // Hubris targets are little-endian.
+    let mut buf = [0; 16];
+    buf[..bytes.len().min(16)].copy_from_slice(&bytes[..bytes.len().min(16)]);
+    let unsigned = u128::from_le_bytes(buf);
+    let signed = |bits: u32| {
+        let shift = 128 - bits;
+        Value::Signed((unsigned << shift) as i128 >> shift)
+    };
+    let value = match ty {
+        "()" => Value::Unit,
+        "bool" => match unsigned {
+            0 => Value::Bool(false),
+            1 => Value::Bool(true),
+            n => return Err(format!("{} is not a valid bool", n)),
+        },
+        "char" => match char::from_u32(unsigned as u32) {
+            Some(c) => Value::Char(c),
+            None => return Err(format!("{:#x} is not a valid char", unsigned)),
+        },
+        "u8" | "u16" | "u32" | "u64" | "u128" | "usize" => {
+            Value::Unsigned(unsigned)
+        }
+        "i8" | "i16" | "i32" | "i64" | "i128" | "isize" => {
+            signed(bytes.len() as u32 * 8)
+        }
+        "f32" => Value::Float(f32::from_bits(unsigned as u32).into()),
+        "f64" => Value::Float(f64::from_bits(unsigned as u64)),
+        _ => Value::Bytes(bytes.to_vec()),
+    };
+    Ok(value)
+}
@@ -0,0 +1,304 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Comparison of two versions of an interface.
+//!
+//! Clients and servers are built separately, and agree on an interface only by
+//! both being generated from the same definition. This module reports the
+//! changes between two definitions and which of them would break a client or
+//! server built against the old one.
+
+use super::{common, syntax};
+use std::fmt;
+
+/// A single difference between two versions of an interface.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    /// The interface itself was renamed. This changes the names of generated
+    /// types, but not anything on the wire.
+    Renamed { old: String, new: String },
+    /// An operation was added after all existing operations.
+    OpAdded { op: String, number: u16 },
+    /// An operation was removed. Clients still sending it will get errors.
+    OpRemoved { op: String, number: u16 },
+    /// An operation is still present, but has a different number, usually
+    /// because an operation before it was added or removed.
+    OpRenumbered { op: String, old: u16, new: u16 },
+    /// Something about an operation's signature changed.
+    OpChanged {
+        op: String,
+        what: String,
+        breaking: bool,
+    },
+}
+
+impl Change {
+    /// Checks whether this change means that existing clients and servers
+    /// can't talk to updated ones.
+    pub fn is_breaking(&self) -> bool {
+        match self {
+            Self::Renamed { .. } | Self::OpAdded { .. } => false,
+            Self::OpRemoved { .. } | Self::OpRenumbered { .. } => true,
+            Self::OpChanged { breaking, .. } => *breaking,
+        }
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let tag = if self.is_breaking() {
+            "breaking"
+        } else {
+            "compatible"
+        };
+        write!(f, "{}: ", tag)?;
+        match self {
+            Self::Renamed { old, new } => {
+                write!(f, "interface renamed from {} to {}", old, new)
+            }
+            Self::OpAdded { op, number } => {
+                write!(f, "operation {} added as {}", op, number)
+            }
+            Self::OpRemoved { op, number } => {
+                write!(f, "operation {} ({}) removed", op, number)
+            }
+            Self::OpRenumbered { op, old, new } => {
+                write!(f, "operation {} renumbered from {} to {}", op, old, new)
+            }
+            Self::OpChanged { op, what, .. } => {
+                write!(f, "operation {}: {}", op, what)
+            }
+        }
+    }
+}
+
+/// Lists the changes needed to get from `old` to `new`.
+///
+/// Operations are matched up by name, so renaming an operation shows up as
+/// one being removed and another added.
+pub fn diff(old: &syntax::Interface, new: &syntax::Interface) -> Vec<Change> {
+    let mut changes = vec![];
+
+    if old.name != new.name {
+        changes.push(Change::Renamed {
+            old: old.name.clone(),
+            new: new.name.clone(),
+        });
+    }
+
+    for (idx, (name, old_op)) in old.ops.iter().enumerate() {
+        let old_number = idx as u16 + 1;
+        match new.ops.get_full(name) {
+            None => changes.push(Change::OpRemoved {
+                op: name.clone(),
+                number: old_number,
+            }),
+            Some((new_idx, _, new_op)) => {
+                let new_number = new_idx as u16 + 1;
+                if new_number != old_number {
+                    changes.push(Change::OpRenumbered {
+                        op: name.clone(),
+                        old: old_number,
+                        new: new_number,
+                    });
+                }
+                diff_op(name, old_op, new_op, &mut changes);
+            }
+        }
+    }
+
+    for (idx, (name, _)) in new.ops.iter().enumerate() {
+        if !old.ops.contains_key(name) {
+            changes.push(Change::OpAdded {
+                op: name.clone(),
+                number: idx as u16 + 1,
+            });
+        }
+    }
+
+    changes
+}
+
+fn diff_op(
+    name: &str,
+    old: &syntax::Operation,
+    new: &syntax::Operation,
+    changes: &mut Vec<Change>,
+) {
+    let mut change = |what: String, breaking: bool| {
+        changes.push(Change::OpChanged {
+            op: name.to_string(),
+            what,
+            breaking,
+        })
+    };
+
+    // Arguments are packed in order, so any difference in names, order, or
+    // types moves bytes around. Names alone don't affect the wire format, but
+    // they do affect the generated client and server signatures.
+    let shape = |args: &indexmap::IndexMap<String, syntax::AttributedTy>| {
+        args.iter()
+            .map(|(n, a)| (n.clone(), a.ty.clone(), a.recv.clone()))
+            .collect::<Vec<_>>()
+    };
+    if shape(&old.args) != shape(&new.args) {
+        let describe =
+            |args: &indexmap::IndexMap<String, syntax::AttributedTy>| {
+                args.iter()
+                    .map(|(n, a)| format!("{}: {}", n, a.display()))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            };
+        change(
+            format!(
+                "arguments changed from ({}) to ({})",
+                describe(&old.args),
+                describe(&new.args)
+            ),
+            true,
+        );
+    } else {
+        for ((aname, o), n) in old.args.iter().zip(new.args.values()) {
+            if (&o.range, &o.one_of, &o.validate)
+                == (&n.range, &n.one_of, &n.validate)
+            {
+                continue;
+            }
+            // We can't tell in general whether new constraints accept
+            // everything the old ones did, so only dropping them is safe.
+            let describe = |a: &syntax::AttributedTy| {
+                common::describe_constraints(a)
+                    .unwrap_or_else(|| "anything".to_string())
+            };
+            change(
+                format!(
+                    "argument {} constraints changed: was {}, now {}",
+                    aname,
+                    describe(o),
+                    describe(n)
+                ),
+                n.is_constrained(),
+            );
+        }
+    }
+
+    let old_leases = old.leases.keys().collect::<Vec<_>>();
+    let new_leases = new.leases.keys().collect::<Vec<_>>();
+    if old_leases != new_leases {
+        change(
+            format!("leases changed from {:?} to {:?}", old_leases, new_leases),
+            true,
+        );
+    } else {
+        for ((lname, o), n) in old.leases.iter().zip(new.leases.values()) {
+            if o.ty != n.ty {
+                change(
+                    format!(
+                        "lease {} type changed from {} to {}",
+                        lname, o.ty.0, n.ty.0
+                    ),
+                    true,
+                );
+            }
+            // Gaining access rights breaks old clients, which won't have
+            // granted them; losing them is harmless.
+            if (n.read && !o.read) || (n.write && !o.write) {
+                change(format!("lease {} requires more access", lname), true);
+            } else if n.read != o.read || n.write != o.write {
+                change(format!("lease {} requires less access", lname), false);
+            }
+            if o.max_len != n.max_len {
+                // A lower limit rejects leases that used to be accepted.
+                let breaking = match (o.max_len, n.max_len) {
+                    (_, None) => false,
+                    (None, Some(_)) => true,
+                    (Some(o), Some(n)) => n < o,
+                };
+                change(
+                    format!(
+                        "lease {} max_len changed from {:?} to {:?}",
+                        lname,
+                        o.max_len.map(|n| n.get()),
+                        n.max_len.map(|n| n.get())
+                    ),
+                    breaking,
+                );
+            }
+            if o.min_len != n.min_len {
+                // A higher minimum rejects leases that used to be accepted.
+                let breaking = match (o.min_len, n.min_len) {
+                    (_, None) => false,
+                    (None, Some(_)) => true,
+                    (Some(o), Some(n)) => n > o,
+                };
+                change(
+                    format!(
+                        "lease {} min_len changed from {:?} to {:?}",
+                        lname,
+                        o.min_len.map(|n| n.get()),
+                        n.min_len.map(|n| n.get())
+                    ),
+                    breaking,
+                );
+            }
+        }
+    }
+
+    if old.reply != new.reply {
+        change("reply type changed".to_string(), true);
+    }
+
+    if old.idempotent != new.idempotent {
+        // This changes whether the client stub retries or reports server
+        // death, which changes its error type's requirements.
+        change(
+            format!(
+                "idempotent changed from {} to {}",
+                old.idempotent, new.idempotent
+            ),
+            true,
+        );
+    }
+
+    if old.allowed_callers != new.allowed_callers
+        || old.deny_callers != new.deny_callers
+    {
+        // Breaking if a task that could call the operation before can't now.
+        // An allow list excludes tasks that nobody has named yet, so replacing
+        // a deny list (or no list) with one always is.
+        let breaking = if new.allowed_callers.is_empty() {
+            new.deny_callers.iter().any(|t| allows(old, t))
+        } else {
+            old.allowed_callers.is_empty()
+                || old.allowed_callers.iter().any(|t| !allows(new, t))
+        };
+        let callers = |op: &syntax::Operation| {
+            if !op.allowed_callers.is_empty() {
+                format!("only {}", op.allowed_callers.join(", "))
+            } else if !op.deny_callers.is_empty() {
+                format!("all but {}", op.deny_callers.join(", "))
+            } else {
+                "any task".to_string()
+            }
+        };
+        change(
+            format!(
+                "callers changed from {} to {}",
+                callers(old),
+                callers(new)
+            ),
+            breaking,
+        );
+    }
+}
+
+/// Checks whether `op` may be called by the task named `task`.
+fn allows(op: &syntax::Operation, task: &str) -> bool {
+    let named = |list: &[String]| list.iter().any(|t| t == task);
+    if op.allowed_callers.is_empty() {
+        !named(&op.deny_callers)
+    } else {
+        named(&op.allowed_callers)
+    }
+}
@@ -0,0 +1,108 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Markdown reference documentation for interfaces.
+
+use super::layout::{self, TypeSizes};
+use super::{common, syntax, Error};
+
+/// Writes a Markdown description of `iface` to `out`: one section per
+/// operation, giving its number, arguments, leases, and reply.
+pub fn generate_markdown(
+    iface: &syntax::Interface,
+    mut out: impl std::io::Write,
+) -> Result<(), Error> {
+    let layouts = layout::op_layouts(iface, &TypeSizes::new());
+
+    writeln!(out, "# `{}` interface", iface.name)?;
+    writeln!(out)?;
+    writeln!(out, "| Number | Operation |")?;
+    writeln!(out, "|-------:|-----------|")?;
+    for l in &layouts {
+        writeln!(out, "| {} | [`{}`](#{}) |", l.number, l.name, l.name)?;
+    }
+
+    for ((name, op), l) in iface.ops.iter().zip(&layouts) {
+        writeln!(out)?;
+        writeln!(out, "## `{}`", name)?;
+        writeln!(out)?;
+        writeln!(out, "Operation number {}.", l.number)?;
+        if op.idempotent {
+            writeln!(
+                out,
+                "Idempotent: clients retry automatically if the server restarts."
+            )?;
+        }
+        if let Some(callers) = common::describe_callers(op) {
+            writeln!(out, "{}", callers)?;
+        }
+
+        writeln!(out)?;
+        if op.args.is_empty() {
+            writeln!(out, "No arguments.")?;
+        } else {
+            writeln!(out, "| Argument | Type | Sent as |")?;
+            writeln!(out, "|----------|------|---------|")?;
+            for (argname, arg) in &op.args {
+                writeln!(
+                    out,
+                    "| `{}` | `{}` | `{}` |",
+                    argname,
+                    arg.ty.0,
+                    arg.repr_ty().0
+                )?;
+            }
+            for (argname, arg) in &op.args {
+                if let Some(c) = common::describe_constraints(arg) {
+                    writeln!(out)?;
+                    writeln!(out, "`{}` must be {}.", argname, c)?;
+                }
+            }
+        }
+
+        if !op.leases.is_empty() {
+            writeln!(out)?;
+            writeln!(
+                out,
+                "| Lease | Type | Access | Min length | Max length |"
+            )?;
+            writeln!(
+                out,
+                "|-------|------|--------|------------|------------|"
+            )?;
+            for (leasename, lease) in &op.leases {
+                let len = |n: Option<std::num::NonZeroU32>| {
+                    n.map(|n| n.to_string()).unwrap_or_else(|| "-".to_string())
+                };
+                writeln!(
+                    out,
+                    "| `{}` | `{}` | {} | {} | {} |",
+                    leasename,
+                    lease.ty.0,
+                    layout::access_name(lease.read, lease.write),
+                    len(lease.min_len),
+                    len(lease.max_len)
+                )?;
+            }
+        }
+
+        writeln!(out)?;
+        match &op.reply {
+            syntax::Reply::Result { ok, err } => {
+                match ok.ty.bounded() {
+                    Some((elt, n)) => {
+                        write!(out, "Returns up to {} values of `{}`", n, elt)?
+                    }
+                    None => write!(out, "Returns `{}`", ok.ty.0)?,
+                }
+                match err {
+                    syntax::Error::CLike(ty) => {
+                        writeln!(out, ", or fails with `{}`.", ty.0)?
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
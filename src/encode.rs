@@ -0,0 +1,300 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Host-side encoding of IPC calls, for tools that talk to a running system.
+//!
+//! This is the inverse of `decode`: given an interface, an operation, and
+//! values for its arguments, it produces exactly what a generated client would
+//! send, so that a debugger can make the call on a tool's behalf.
+//!
+//! Argument values are JSON. Numbers may also be given as strings, in decimal
+//! or `0x`-prefixed hex, which is the only way to write 128-bit values that
+//! don't fit in a JSON number. `char`s are one-character strings, arrays are
+//! arrays, and types other than primitives and arrays (whose sizes must be in
+//! the `TypeSizes`) are hex strings or arrays of bytes, in target byte order.
+//! Every value is checked against the range of its type.
+
+use super::layout::{self, LeaseLayout, TypeSizes};
+use super::{syntax, Error};
+use serde::Serialize;
+use serde_json::Value;
+use std::fmt;
+
+/// An encoded call, ready to send.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Call {
+    pub op: String,
+    /// Operation number, as sent on the wire.
+    pub number: u16,
+    /// The argument message.
+    pub message: Vec<u8>,
+    /// Size of the reply buffer the client must provide.
+    pub reply_size: usize,
+    /// Leases the call must be sent with, in order.
+    pub leases: Vec<LeaseLayout>,
+}
+
+impl fmt::Display for Call {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "operation {} ({})", self.number, self.op)?;
+        write!(f, "message:")?;
+        for b in &self.message {
+            write!(f, " {:02x}", b)?;
+        }
+        writeln!(f)?;
+        writeln!(f, "reply buffer: {} bytes", self.reply_size)?;
+        for (i, lease) in self.leases.iter().enumerate() {
+            write!(
+                f,
+                "lease {}: {} {} {}",
+                i,
+                lease.name,
+                lease.access(),
+                lease.ty
+            )?;
+            match (lease.min_len, lease.max_len) {
+                (Some(min), Some(max)) => {
+                    write!(f, " ({} to {} elements)", min, max)?
+                }
+                (Some(min), None) => write!(f, " (at least {} elements)", min)?,
+                (None, Some(max)) => write!(f, " (at most {} elements)", max)?,
+                (None, None) => {}
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Encodes a call to `op` in `iface`, with arguments given as a JSON object
+/// mapping each argument's name to its value.
+///
+/// `sizes` supplies the sizes of any application-defined types.
+pub fn encode_call(
+    iface: &syntax::Interface,
+    sizes: &TypeSizes,
+    op: &str,
+    args: &Value,
+) -> Result<Call, Error> {
+    let error = |msg: String| Error::Encode(format!("{}: {}", op, msg));
+
+    let layout = layout::op_layouts(iface, sizes)
+        .into_iter()
+        .find(|l| l.name == op)
+        .ok_or_else(|| {
+            Error::Encode(format!("{} has no operation `{}`", iface.name, op))
+        })?;
+
+    let args = args
+        .as_object()
+        .ok_or_else(|| error("arguments must be a JSON object".to_string()))?;
+    if let Some(extra) = args
+        .keys()
+        .find(|k| !layout.args.iter().any(|a| &a.name == *k))
+    {
+        return Err(error(format!("no argument named `{}`", extra)));
+    }
+
+    let mut message = vec![];
+    for arg in &layout.args {
+        let value = args
+            .get(&arg.name)
+            .ok_or_else(|| error(format!("missing argument `{}`", arg.name)))?;
+        encode_value(sizes, &arg.ty, value, &mut message)
+            .map_err(|e| error(format!("{}: {}", arg.name, e)))?;
+    }
+
+    let reply_size = layout.reply.size.ok_or_else(|| {
+        error(format!("size of reply type {} is unknown", layout.reply.ty))
+    })?;
+
+    Ok(Call {
+        op: layout.name,
+        number: layout.number,
+        message,
+        reply_size,
+        leases: layout.leases,
+    })
+}
+
+/// Encodes a call like `encode_call`, with arguments given as `(name, value)`
+/// strings, as from a command line. Each value is parsed as JSON; values that
+/// aren't valid JSON are taken as strings, so that `c=x` works for a `char`.
+pub fn encode_call_str(
+    iface: &syntax::Interface,
+    sizes: &TypeSizes,
+    op: &str,
+    args: &[(String, String)],
+) -> Result<Call, Error> {
+    let args = args
+        .iter()
+        .map(|(name, text)| {
+            let value = serde_json::from_str(text)
+                .unwrap_or_else(|_| Value::String(text.clone()));
+            (name.clone(), value)
+        })
+        .collect();
+    encode_call(iface, sizes, op, &Value::Object(args))
+}
+
+/// Appends the encoding of `value`, as a `ty`, to `out`.
+fn encode_value(
+    sizes: &TypeSizes,
+    ty: &str,
+    value: &Value,
+    out: &mut Vec<u8>,
+) -> Result<(), String> {
+    let ty = ty.trim();
+    if let Some((elt, len)) = layout::parse_array(ty) {
+        let elts = value
+            .as_array()
+            .ok_or_else(|| format!("expected an array for {}", ty))?;
+        if elts.len() != len {
+            return Err(format!(
+                "expected {} elements for {}, got {}",
+                len,
+                ty,
+                elts.len()
+            ));
+        }
+        for e in elts {
+            encode_value(sizes, elt, e, out)?;
+        }
+        return Ok(());
+    }
+
+    let size = match layout::primitive_size(ty) {
+        Some(size) => size,
+        None => return encode_bytes(sizes, ty, value, out),
+    };
+    // Hubris targets are little-endian.
+    let bytes = match ty {
+        "()" => match value {
+            Value::Null => vec![],
+            _ => return Err("expected null for ()".to_string()),
+        },
+        "bool" => match value {
+            Value::Bool(b) => vec![u8::from(*b)],
+            _ => return Err("expected true or false".to_string()),
+        },
+        "char" => {
+            let c = match value {
+                Value::String(s) if s.chars().count() == 1 => {
+                    s.chars().next().unwrap_or_default()
+                }
+                _ => {
+                    let n = unsigned(value)?;
+                    u32::try_from(n)
+                        .ok()
+                        .and_then(char::from_u32)
+                        .ok_or_else(|| format!("{:#x} is not a char", n))?
+                }
+            };
+            u32::from(c).to_le_bytes().to_vec()
+        }
+        "f32" => {
+            let n = float(value)?;
+            if n.is_finite() && n.abs() > f64::from(f32::MAX) {
+                return Err(format!("{} is out of range for f32", n));
+            }
+            (n as f32).to_le_bytes().to_vec()
+        }
+        "f64" => float(value)?.to_le_bytes().to_vec(),
+        "u8" | "u16" | "u32" | "u64" | "u128" | "usize" => {
+            let n = unsigned(value)?;
+            if size < 16 && n >> (size * 8) != 0 {
+                return Err(format!("{} is out of range for {}", n, ty));
+            }
+            n.to_le_bytes()[..size].to_vec()
+        }
+        _ => {
+            // Signed integers are all that's left.
+            let n = signed(value)?;
+            let bits = size * 8;
+            if bits < 128 && (n >> (bits - 1) != 0 && n >> (bits - 1) != -1) {
+                return Err(format!("{} is out of range for {}", n, ty));
+            }
+            n.to_le_bytes()[..size].to_vec()
+        }
+    };
+    out.extend_from_slice(&bytes);
+    Ok(())
+}
+
+/// Appends the raw bytes given for a value of application-defined type `ty`.
+fn encode_bytes(
+    sizes: &TypeSizes,
+    ty: &str,
+    value: &Value,
+    out: &mut Vec<u8>,
+) -> Result<(), String> {
+    let size = sizes
+        .size_of(ty)
+        .ok_or_else(|| format!("size of type {} is unknown", ty))?;
+    let bytes = match value {
+        Value::String(s) => parse_hex(s),
+        Value::Array(elts) => elts
+            .iter()
+            .map(|e| e.as_u64().and_then(|b| u8::try_from(b).ok()))
+            .collect(),
+        _ => None,
+    }
+    .ok_or_else(|| format!("expected hex or an array of bytes for {}", ty))?;
+    if bytes.len() != size {
+        return Err(format!(
+            "expected {} bytes for {}, got {}",
+            size,
+            ty,
+            bytes.len()
+        ));
+    }
+    out.extend_from_slice(&bytes);
+    Ok(())
+}
+
+/// Parses hex digits, optionally with a `0x` prefix and spaces, into bytes.
+pub fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    let digits = s.chars().filter(|c| !c.is_whitespace()).collect::<String>();
+    let digits = digits.strip_prefix("0x").unwrap_or(&digits);
+    digits
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .filter(|pair| pair.len() == 2)
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+        })
+        .collect()
+}
+
+fn unsigned(value: &Value) -> Result<u128, String> {
+    match value {
+        Value::Number(n) => n.as_u64().map(u128::from),
+        Value::String(s) => match s.strip_prefix("0x") {
+            Some(hex) => u128::from_str_radix(hex, 16).ok(),
+            None => s.parse().ok(),
+        },
+        _ => None,
+    }
+    .ok_or_else(|| format!("expected an unsigned integer, got {}", value))
+}
+
+fn signed(value: &Value) -> Result<i128, String> {
+    match value {
+        Value::Number(n) => n.as_i64().map(i128::from),
+        Value::String(s) => match s.strip_prefix("0x") {
+            Some(hex) => i128::from_str_radix(hex, 16).ok(),
+            None => s.parse().ok(),
+        },
+        _ => None,
+    }
+    .ok_or_else(|| format!("expected an integer, got {}", value))
+}
+
+fn float(value: &Value) -> Result<f64, String> {
+    value
+        .as_f64()
+        .ok_or_else(|| format!("expected a number, got {}", value))
+}
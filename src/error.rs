@@ -0,0 +1,133 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Errors produced by the Idol compiler.
+
+use std::fmt;
+use std::path::PathBuf;
+
+/// Everything that can go wrong while loading an interface definition and
+/// generating code from it.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing the file at `path` failed.
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// The interface definition is not valid RON, or doesn't match the syntax
+    /// described in `syntax`.
+    ///
+    /// `line` and `column` are 1-based, and are both zero if the parser didn't
+    /// report a location.
+    Parse {
+        path: Option<PathBuf>,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    /// The interface definition parsed, but describes something we can't
+    /// generate code for.
+    Validation(String),
+    /// Writing generated code to its destination failed.
+    Codegen(std::io::Error),
+    /// An environment variable we need, such as `OUT_DIR`, isn't set. This
+    /// usually means a `build_*` function was called from outside a build
+    /// script.
+    MissingEnv(&'static str),
+    /// A compiled image couldn't be read as ELF, or an interface definition
+    /// embedded in it is malformed.
+    Extract(String),
+    /// A captured message or reply doesn't fit the interface it's being
+    /// decoded with.
+    Decode(String),
+    /// Values given for a call don't fit the interface it's being encoded
+    /// with.
+    Encode(String),
+}
+
+impl Error {
+    /// Records the file that a `Parse` error came from. Other errors are
+    /// returned unchanged.
+    pub fn with_path(self, path: impl Into<PathBuf>) -> Self {
+        match self {
+            Self::Parse {
+                line,
+                column,
+                message,
+                ..
+            } => Self::Parse {
+                path: Some(path.into()),
+                line,
+                column,
+                message,
+            },
+            other => other,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io { path, source } => {
+                write!(f, "{}: {}", path.display(), source)
+            }
+            Self::Parse {
+                path,
+                line,
+                column,
+                message,
+            } => {
+                if let Some(path) = path {
+                    write!(f, "{}: ", path.display())?;
+                }
+                if *line != 0 {
+                    write!(f, "line {}, column {}: ", line, column)?;
+                }
+                write!(f, "{}", message)
+            }
+            Self::Validation(msg) => write!(f, "invalid interface: {}", msg),
+            Self::Codegen(e) => write!(f, "writing generated code: {}", e),
+            Self::MissingEnv(var) => write!(
+                f,
+                "environment variable {} is not set (is this running from \
+                 build.rs?)",
+                var
+            ),
+            Self::Extract(msg) => write!(f, "extracting interfaces: {}", msg),
+            Self::Decode(msg) => write!(f, "decoding: {}", msg),
+            Self::Encode(msg) => write!(f, "encoding: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io { source, .. } => Some(source),
+            Self::Codegen(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ron::Error> for Error {
+    fn from(e: ron::Error) -> Self {
+        Self::Parse {
+            path: None,
+            line: e.position.line,
+            column: e.position.col,
+            message: e.code.to_string(),
+        }
+    }
+}
+
+/// Errors writing to an `impl Write` don't come with a path, so we assume
+/// they're from writing generated code.
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Self::Codegen(e)
+    }
+}
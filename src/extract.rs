@@ -0,0 +1,214 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Recovery of interface definitions from compiled images.
+//!
+//! Generated server support code places the interface definition in a static
+//! named `_<NAME>_IDOL_DEFINITION` in the `.idolatry` section (see
+//! `Generator::emit_section`). This module finds those statics in an ELF file
+//! and parses them back into `Interface`s, so that we can tell exactly which
+//! interfaces a deployed image was built with.
+//!
+//! Definitions are embedded in one of the forms described by
+//! `SectionEncoding`. The compact form starts with `COMPACT_MAGIC`, then a
+//! version byte (currently `COMPACT_VERSION`), then the minified definition.
+//! The magic starts with a NUL, which can't begin a source-form definition, so
+//! the two are never confused.
+
+use super::{syntax, Error, SectionEncoding};
+use goblin::elf::{section_header::SHT_NOBITS, Elf};
+use std::path::Path;
+
+/// Name of the section that interface definitions are placed in.
+pub const SECTION: &str = ".idolatry";
+
+/// Marks a definition embedded using `SectionEncoding::Compact`.
+pub const COMPACT_MAGIC: [u8; 4] = *b"\0IDL";
+
+/// Version of the compact encoding produced by this crate.
+pub const COMPACT_VERSION: u8 = 1;
+
+/// An interface definition found in an image.
+#[derive(Clone, Debug)]
+pub struct Embedded {
+    /// Name of the symbol holding the definition.
+    pub symbol: String,
+    /// How the definition was embedded.
+    pub encoding: SectionEncoding,
+    /// The definition's text, without any header. For the compact encoding,
+    /// this is the minified form; use `Interface::to_text` for something
+    /// readable.
+    pub text: String,
+    /// The parsed definition.
+    pub interface: syntax::Interface,
+}
+
+/// Finds and parses every interface definition embedded in the ELF image at
+/// `path`.
+pub fn extract_file(path: impl AsRef<Path>) -> Result<Vec<Embedded>, Error> {
+    let path = path.as_ref();
+    let bytes = std::fs::read(path).map_err(|source| Error::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    extract(&bytes).map_err(|e| match e {
+        Error::Extract(msg) => {
+            Error::Extract(format!("{}: {}", path.display(), msg))
+        }
+        e => e,
+    })
+}
+
+/// Finds and parses every interface definition embedded in the ELF image in
+/// `bytes`, in symbol table order.
+pub fn extract(bytes: &[u8]) -> Result<Vec<Embedded>, Error> {
+    let elf = Elf::parse(bytes).map_err(|e| Error::Extract(e.to_string()))?;
+
+    let section =
+        elf.section_headers.iter().enumerate().find(|(_, sh)| {
+            elf.shdr_strtab.get_at(sh.sh_name) == Some(SECTION)
+        });
+    let (shndx, sh) = match section {
+        Some(s) => s,
+        // An image with no servers in it doesn't have the section at all.
+        None => return Ok(vec![]),
+    };
+    if sh.sh_type == SHT_NOBITS {
+        return Err(Error::Extract(format!("{} has no contents", SECTION)));
+    }
+
+    let mut found = vec![];
+    for sym in elf.syms.iter() {
+        if sym.st_shndx != shndx {
+            continue;
+        }
+        let symbol = match elf.strtab.get_at(sym.st_name) {
+            Some(name) if is_definition_symbol(item_name(name)) => name,
+            _ => continue,
+        };
+
+        // Symbol values are addresses, so convert to an offset in the file
+        // via the section's address.
+        let start = sym
+            .st_value
+            .checked_sub(sh.sh_addr)
+            .filter(|off| match off.checked_add(sym.st_size) {
+                Some(end) => end <= sh.sh_size,
+                None => false,
+            })
+            .and_then(|off| sh.sh_offset.checked_add(off))
+            .ok_or_else(|| {
+                Error::Extract(format!("{} lies outside {}", symbol, SECTION))
+            })?;
+        let data = start
+            .checked_add(sym.st_size)
+            .and_then(|end| bytes.get(start as usize..end as usize))
+            .ok_or_else(|| {
+                Error::Extract(format!("{} lies outside the file", symbol))
+            })?;
+
+        found.push(decode(symbol, data)?);
+    }
+    Ok(found)
+}
+
+/// Produces the bytes to embed for `iface`, parsed from `text`, using
+/// `encoding`.
+pub fn encode(
+    iface: &syntax::Interface,
+    text: &str,
+    encoding: SectionEncoding,
+) -> Vec<u8> {
+    match encoding {
+        SectionEncoding::Source => text.as_bytes().to_vec(),
+        SectionEncoding::Compact => {
+            // The serializer only fails for types RON can't represent, and we
+            // don't use any of those.
+            let body = ron::ser::to_string(iface)
+                .expect("interface should always serialize");
+            let mut bytes = COMPACT_MAGIC.to_vec();
+            bytes.push(COMPACT_VERSION);
+            bytes.extend_from_slice(body.as_bytes());
+            bytes
+        }
+    }
+}
+
+/// Decodes the contents of the definition static named `symbol`, in either
+/// encoding.
+pub fn decode(symbol: &str, data: &[u8]) -> Result<Embedded, Error> {
+    let error = |msg: String| Error::Extract(format!("{}: {}", symbol, msg));
+
+    let (encoding, body) = match data.strip_prefix(&COMPACT_MAGIC[..]) {
+        None => (SectionEncoding::Source, data),
+        Some([COMPACT_VERSION, body @ ..]) => (SectionEncoding::Compact, body),
+        Some([version, ..]) => {
+            return Err(error(format!(
+                "unsupported compact encoding version {}",
+                version
+            )))
+        }
+        Some([]) => return Err(error("truncated header".to_string())),
+    };
+
+    let text = std::str::from_utf8(body)
+        .map_err(|e| error(e.to_string()))?
+        .to_string();
+    let interface =
+        syntax::Interface::from_str(&text).map_err(|e| error(e.to_string()))?;
+    Ok(Embedded {
+        symbol: symbol.to_string(),
+        encoding,
+        text,
+        interface,
+    })
+}
+
+/// Checks whether `name` fits the pattern used for definition statics.
+fn is_definition_symbol(name: &str) -> bool {
+    let iface = name
+        .strip_prefix('_')
+        .and_then(|n| n.strip_suffix("_IDOL_DEFINITION"));
+    matches!(iface, Some(n) if !n.is_empty())
+}
+
+/// Returns the name of the item that `symbol` refers to. Definition statics
+/// aren't `#[no_mangle]`, so they get legacy Rust mangled names like
+/// `_ZN4task21_ECHO_IDOL_DEFINITION17h0123456789abcdefE`; for those, this is
+/// the last path segment other than the hash. Any other symbol is returned
+/// unchanged.
+fn item_name(symbol: &str) -> &str {
+    let mut rest = match symbol.strip_prefix("_ZN") {
+        Some(rest) => rest,
+        None => return symbol,
+    };
+    let mut last = None;
+    while !rest.starts_with('E') {
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        let segment = rest[..digits]
+            .parse::<usize>()
+            .ok()
+            .and_then(|len| len.checked_add(digits))
+            .and_then(|end| rest.get(digits..end));
+        let segment = match segment {
+            Some(segment) => segment,
+            None => return symbol,
+        };
+        if !is_hash(segment) {
+            last = Some(segment);
+        }
+        rest = &rest[digits + segment.len()..];
+    }
+    last.unwrap_or(symbol)
+}
+
+/// Checks whether `segment` is the hash that ends a legacy mangled name.
+fn is_hash(segment: &str) -> bool {
+    match segment.strip_prefix('h') {
+        Some(hex) => {
+            hex.len() == 16 && hex.bytes().all(|b| b.is_ascii_hexdigit())
+        }
+        None => false,
+    }
+}
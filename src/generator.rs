@@ -0,0 +1,345 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Configurable entry point to code generation.
+//!
+//! The free functions in `client` and `server` generate code with default
+//! settings. `Generator` exposes the knobs behind them, and is built up by
+//! chaining:
+//!
+//! ```ignore
+//! idol::Generator::new()
+//!     .server_style(idol::server::ServerStyle::InOrder)
+//!     .emit_section(false)
+//!     .build_server_support("../../idl/my-interface.idol", "server_stub.rs")?;
+//! ```
+
+use super::{client, server, syntax, Error};
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// What generated code should do when it detects a problem that the caller
+/// could have prevented, such as an over-long lease or a malformed reply.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ErrorPolicy {
+    /// Panic, without a message to save ROM space. This is the default.
+    Panic,
+    /// Return the corresponding `idol_runtime::ClientError` to the caller,
+    /// converted into the operation's error type. The error types used in the
+    /// interface must implement `From<idol_runtime::ClientError>`.
+    Return,
+}
+
+/// How server support code embeds the interface definition in `.idolatry`.
+///
+/// `idol::extract` can read either form back.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SectionEncoding {
+    /// The source text, byte for byte, including comments and whitespace. This
+    /// is the default.
+    Source,
+    /// A short versioned header followed by the interface serialized as RON
+    /// with all optional whitespace and defaulted fields removed. This is
+    /// usually much smaller, at the cost of losing comments and formatting.
+    Compact,
+}
+
+/// Settings for code generation.
+#[derive(Clone, Debug)]
+pub struct Generator {
+    pub(crate) out_dir: Option<PathBuf>,
+    pub(crate) server_style: server::ServerStyle,
+    pub(crate) userlib: String,
+    pub(crate) zerocopy: String,
+    pub(crate) idol_runtime: String,
+    pub(crate) emit_section: bool,
+    pub(crate) section_encoding: SectionEncoding,
+    pub(crate) error_policy: ErrorPolicy,
+    pub(crate) module: Option<String>,
+    pub(crate) docs: bool,
+    pub(crate) handshake: bool,
+    pub(crate) reflection: bool,
+    pub(crate) api_trait: bool,
+    pub(crate) mock: bool,
+    pub(crate) fuzz_harness: bool,
+    pub(crate) counters: bool,
+}
+
+impl Default for Generator {
+    fn default() -> Self {
+        Self {
+            out_dir: None,
+            server_style: server::ServerStyle::InOrder,
+            userlib: "userlib".to_string(),
+            zerocopy: "zerocopy".to_string(),
+            idol_runtime: "idol_runtime".to_string(),
+            emit_section: true,
+            section_encoding: SectionEncoding::Source,
+            error_policy: ErrorPolicy::Panic,
+            module: None,
+            docs: false,
+            handshake: false,
+            reflection: false,
+            api_trait: false,
+            mock: false,
+            fuzz_harness: false,
+            counters: false,
+        }
+    }
+}
+
+impl Generator {
+    /// Creates a `Generator` with default settings, which produce the same code
+    /// as the free functions in `client` and `server`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the directory that the `build_*` functions write into. By default,
+    /// they use `OUT_DIR` from the environment, as set by Cargo for build
+    /// scripts.
+    pub fn out_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.out_dir = Some(dir.into());
+        self
+    }
+
+    /// Sets the style of server trait to generate. The default is `InOrder`.
+    pub fn server_style(mut self, style: server::ServerStyle) -> Self {
+        self.server_style = style;
+        self
+    }
+
+    /// Sets the path used to reach the Hubris `userlib` crate from generated
+    /// code. The default is `userlib`.
+    ///
+    /// Generated code refers to everything it uses through these paths (or
+    /// through `::core`), so it doesn't depend on what's imported where it's
+    /// included. The paths may name a re-export in a facade crate. Note,
+    /// however, that the derive macros used by generated code expand to
+    /// references to `zerocopy` and `num_traits` by name, so those crates must
+    /// still be dependencies of the including crate.
+    pub fn userlib_path(mut self, path: impl Into<String>) -> Self {
+        self.userlib = path.into();
+        self
+    }
+
+    /// Sets the path used to reach the `zerocopy` crate from generated code.
+    /// The default is `zerocopy`.
+    pub fn zerocopy_path(mut self, path: impl Into<String>) -> Self {
+        self.zerocopy = path.into();
+        self
+    }
+
+    /// Sets the path used to reach the `idol-runtime` crate from generated
+    /// code. The default is `idol_runtime`.
+    pub fn idol_runtime_path(mut self, path: impl Into<String>) -> Self {
+        self.idol_runtime = path.into();
+        self
+    }
+
+    /// Controls whether server support code embeds the interface definition in
+    /// the `.idolatry` section, for debuggers. The default is `true`.
+    pub fn emit_section(mut self, emit: bool) -> Self {
+        self.emit_section = emit;
+        self
+    }
+
+    /// Sets how the interface definition is embedded when `emit_section` is
+    /// set. The default is `SectionEncoding::Source`.
+    pub fn section_encoding(mut self, encoding: SectionEncoding) -> Self {
+        self.section_encoding = encoding;
+        self
+    }
+
+    /// Sets what generated code does on caller errors. The default is
+    /// `ErrorPolicy::Panic`.
+    pub fn error_policy(mut self, policy: ErrorPolicy) -> Self {
+        self.error_policy = policy;
+        self
+    }
+
+    /// Wraps all generated code in a `pub mod` with the given name, so that
+    /// several interfaces can be pulled into one crate without their generated
+    /// items colliding. By default, code is generated at the top level.
+    pub fn module(mut self, name: impl Into<String>) -> Self {
+        self.module = Some(name.into());
+        self
+    }
+
+    /// Controls whether generated items get rustdoc comments describing the
+    /// interface. The default is `false`.
+    pub fn docs(mut self, docs: bool) -> Self {
+        self.docs = docs;
+        self
+    }
+
+    /// Controls whether generated code takes part in the fingerprint handshake.
+    /// The default is `false`.
+    ///
+    /// When set, generated servers answer the reserved operation
+    /// `syntax::FINGERPRINT_OP` with the interface's fingerprint from within
+    /// `idol_runtime::dispatch`, and generated clients get a
+    /// `check_fingerprint` method that asks for it and compares it with their
+    /// own.
+    pub fn handshake(mut self, handshake: bool) -> Self {
+        self.handshake = handshake;
+        self
+    }
+
+    /// Controls whether generated servers answer the reserved reflection
+    /// operation, `syntax::REFLECT_OP`, with their interface definition. The
+    /// default is `false`.
+    ///
+    /// The definition is encoded as set by `section_encoding`, and can be
+    /// decoded with `extract::decode`. Because `.idolatry` isn't loaded onto
+    /// the target, this stores a second copy of the definition in flash.
+    pub fn reflection(mut self, reflection: bool) -> Self {
+        self.reflection = reflection;
+        self
+    }
+
+    /// Controls whether client stubs include a `<Iface>Api` trait, with one
+    /// method per operation, implemented by the client. The default is
+    /// `false`.
+    ///
+    /// Code written against the trait rather than the client type can be
+    /// handed a mock instead; see `mock`.
+    pub fn api_trait(mut self, api_trait: bool) -> Self {
+        self.api_trait = api_trait;
+        self
+    }
+
+    /// Controls whether client stubs include `Mock<Iface>`, an implementation
+    /// of the `<Iface>Api` trait that answers calls from queued expectations
+    /// and records the calls it receives. Setting this also sets `api_trait`.
+    /// The default is `false`.
+    ///
+    /// The mock uses `std`, which it links itself, so it can be generated into
+    /// `no_std` crates, but only builds where `std` is available. It's meant
+    /// for host tests, e.g. by setting this only when the build script's
+    /// `TARGET` isn't the Hubris target.
+    pub fn mock(mut self, mock: bool) -> Self {
+        self.mock = mock;
+        if mock {
+            self.api_trait = true;
+        }
+        self
+    }
+
+    /// Controls whether server support code includes a harness for fuzzing
+    /// the generated message decoding with `idol_runtime::fuzz`. The default
+    /// is `false`.
+    ///
+    /// The harness runs on the simulated kernel, so the userlib path must be
+    /// `idol_runtime::sim` (or a re-export of it), with `idol-runtime`'s `std`
+    /// feature enabled. It adds a `fuzz_<name>(data: &[u8])` function to call
+    /// from a fuzz target, such as one made with `cargo fuzz`.
+    pub fn fuzz_harness(mut self, fuzz_harness: bool) -> Self {
+        self.fuzz_harness = fuzz_harness;
+        self
+    }
+
+    /// Controls whether server support code includes `<Name>Counters`, a
+    /// `DispatchObserver` that counts the calls to each operation, errors, and
+    /// rejected messages, and a `static` instance of it, `<NAME>_COUNTERS`. The
+    /// default is `false`.
+    ///
+    /// Servers opt in by dispatching with `idol_runtime::dispatch_observed`
+    /// (or `dispatch_n_observed`) and `&<NAME>_COUNTERS`. The counters are
+    /// plain `u32`s in a `#[repr(C)]` struct, so a debugger can read them from
+    /// the task's memory.
+    pub fn counters(mut self, counters: bool) -> Self {
+        self.counters = counters;
+        self
+    }
+
+    /// Generates a client stub for the interface defined in `source`, writing
+    /// it to `stub_name` in the output directory. This is intended to be called
+    /// from `build.rs`.
+    pub fn build_client_stub(
+        &self,
+        source: &str,
+        stub_name: &str,
+    ) -> Result<(), Error> {
+        let (iface, _text) = syntax::Interface::load(source)?;
+        let stub_file = self.create_output(stub_name)?;
+        self.generate_client_stub(&iface, stub_file)?;
+        println!("cargo:rerun-if-changed={}", source);
+        Ok(())
+    }
+
+    /// Generates server support code for the interface defined in `source`,
+    /// writing it to `stub_name` in the output directory. This is intended to
+    /// be called from `build.rs`.
+    pub fn build_server_support(
+        &self,
+        source: &str,
+        stub_name: &str,
+    ) -> Result<(), Error> {
+        let (iface, text) = syntax::Interface::load(source)?;
+        let stub_file = self.create_output(stub_name)?;
+        self.generate_server_support(&iface, &text, stub_file)?;
+        println!("cargo:rerun-if-changed={}", source);
+        Ok(())
+    }
+
+    /// Generates a client stub for `iface` into `out`.
+    pub fn generate_client_stub(
+        &self,
+        iface: &syntax::Interface,
+        mut out: impl Write,
+    ) -> Result<(), Error> {
+        iface.validate()?;
+        self.begin_module(&mut out)?;
+        client::write_client_stub(self, iface, &mut out)?;
+        self.end_module(&mut out)
+    }
+
+    /// Generates server support code for `iface` into `out`.
+    ///
+    /// `text` is the source text the interface was parsed from; if
+    /// `emit_section` is set, it gets embedded in the output (or, with
+    /// `SectionEncoding::Compact`, a compact form of `iface` does).
+    pub fn generate_server_support(
+        &self,
+        iface: &syntax::Interface,
+        text: &str,
+        mut out: impl Write,
+    ) -> Result<(), Error> {
+        iface.validate()?;
+        self.begin_module(&mut out)?;
+        server::write_server_support(self, iface, text, &mut out)?;
+        self.end_module(&mut out)
+    }
+
+    fn create_output(&self, stub_name: &str) -> Result<File, Error> {
+        let dir = match &self.out_dir {
+            Some(dir) => dir.clone(),
+            None => PathBuf::from(
+                env::var_os("OUT_DIR").ok_or(Error::MissingEnv("OUT_DIR"))?,
+            ),
+        };
+        let path = dir.join(stub_name);
+        File::create(&path).map_err(|e| Error::Io { path, source: e })
+    }
+
+    fn begin_module(&self, mut out: impl Write) -> Result<(), Error> {
+        if let Some(name) = &self.module {
+            writeln!(out, "pub mod {} {{", name)?;
+            writeln!(out, "#[allow(unused_imports)]")?;
+            writeln!(out, "use super::*;")?;
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+
+    fn end_module(&self, mut out: impl Write) -> Result<(), Error> {
+        if self.module.is_some() {
+            writeln!(out, "}}")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,299 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Host-side computation of message layouts.
+//!
+//! Generated code packs arguments into a `#[repr(C, packed)]` struct in
+//! declaration order, so each argument starts right where the previous one
+//! ends. That means we can work out where everything lands without a compiler,
+//! as long as we know the size of each type. We know the sizes of primitives
+//! and arrays of them; sizes of other types have to be supplied by the caller.
+//!
+//! Sizes are for the Hubris targets, which are 32-bit.
+
+use super::syntax;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Size of `usize` and `isize` on the targets we generate code for.
+const POINTER_SIZE: usize = 4;
+
+/// Returns the size in bytes of a primitive type, or `None` if `ty` doesn't
+/// name one.
+pub fn primitive_size(ty: &str) -> Option<usize> {
+    let size = match ty {
+        "()" => 0,
+        "u8" | "i8" | "bool" => 1,
+        "u16" | "i16" => 2,
+        "u32" | "i32" | "f32" | "char" => 4,
+        "u64" | "i64" | "f64" => 8,
+        "u128" | "i128" => 16,
+        "usize" | "isize" => POINTER_SIZE,
+        _ => return None,
+    };
+    Some(size)
+}
+
+/// Splits an array type like `[u8; 4]` into its element type and length.
+///
+/// Slices, which have no length, return `None`.
+pub fn parse_array(ty: &str) -> Option<(&str, usize)> {
+    let inner = ty.trim().strip_prefix('[')?.strip_suffix(']')?;
+    let (elt, len) = inner.rsplit_once(';')?;
+    Some((elt.trim(), len.trim().parse().ok()?))
+}
+
+/// Parses a bounded type like `[u8; ..32]`, used for variable-length replies,
+/// into its element type and maximum length.
+pub fn parse_bounded(ty: &str) -> Option<(&str, usize)> {
+    let inner = ty.trim().strip_prefix('[')?.strip_suffix(']')?;
+    let (elt, len) = inner.rsplit_once(';')?;
+    Some((
+        elt.trim(),
+        len.trim().strip_prefix("..")?.trim().parse().ok()?,
+    ))
+}
+
+/// Strips the brackets from a slice type like `[u8]`, returning the element
+/// type.
+pub fn slice_element(ty: &str) -> Option<&str> {
+    let inner = ty.trim().strip_prefix('[')?.strip_suffix(']')?;
+    if inner.contains(';') {
+        None
+    } else {
+        Some(inner.trim())
+    }
+}
+
+/// Returns the element type of a lease of type `ty`, and whether the lease is
+/// a slice of them. `str` leases are slices of `u8`.
+pub fn lease_element(ty: &str) -> (&str, bool) {
+    if ty.trim() == "str" {
+        ("u8", true)
+    } else {
+        match slice_element(ty) {
+            Some(elt) => (elt, true),
+            None => (ty, false),
+        }
+    }
+}
+
+/// Table of type sizes, covering primitives and arrays automatically, plus any
+/// other types that have been added to it.
+#[derive(Clone, Debug, Default)]
+pub struct TypeSizes {
+    known: BTreeMap<String, usize>,
+}
+
+impl TypeSizes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the size of a type that isn't a primitive, such as a struct or
+    /// enum defined by the application.
+    pub fn insert(&mut self, ty: impl Into<String>, size: usize) {
+        self.known.insert(ty.into(), size);
+    }
+
+    /// Returns the size of `ty` in bytes, if we know it.
+    pub fn size_of(&self, ty: &str) -> Option<usize> {
+        let ty = ty.trim();
+        if let Some(size) = primitive_size(ty) {
+            return Some(size);
+        }
+        // Bounded types are measured at their largest.
+        if let Some((elt, len)) = parse_array(ty).or_else(|| parse_bounded(ty))
+        {
+            return self.size_of(elt)?.checked_mul(len);
+        }
+        self.known.get(ty).copied()
+    }
+}
+
+/// Placement of one value within a message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FieldLayout {
+    pub name: String,
+    /// Type as it appears in the message. For arguments that are converted
+    /// on receipt, this is the type before conversion.
+    pub ty: String,
+    /// Offset from the start of the message, if the sizes of all the fields
+    /// before this one are known.
+    pub offset: Option<usize>,
+    pub size: Option<usize>,
+}
+
+/// Description of one lease expected by an operation.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct LeaseLayout {
+    pub name: String,
+    pub ty: String,
+    pub read: bool,
+    pub write: bool,
+    /// Size of the leased type, or of each element if it's a slice.
+    pub element_size: Option<usize>,
+    pub min_len: Option<u32>,
+    pub max_len: Option<u32>,
+}
+
+impl LeaseLayout {
+    /// Returns the access this lease needs; see `access_name`.
+    pub fn access(&self) -> &'static str {
+        access_name(self.read, self.write)
+    }
+}
+
+/// Names the access a lease grants as `R`, `W`, or `RW`, after the runtime's
+/// attribute types. Leases granting nothing are rejected by validation, so
+/// they're not distinguished from read-only ones.
+pub fn access_name(read: bool, write: bool) -> &'static str {
+    match (read, write) {
+        (true, true) => "RW",
+        (false, true) => "W",
+        _ => "R",
+    }
+}
+
+/// Layout of the messages exchanged for a single operation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OpLayout {
+    pub name: String,
+    /// Operation number, as sent on the wire.
+    pub number: u16,
+    pub args: Vec<FieldLayout>,
+    /// Total size of the argument message, if known.
+    pub msg_size: Option<usize>,
+    /// Successful reply value.
+    pub reply: FieldLayout,
+    pub leases: Vec<LeaseLayout>,
+}
+
+/// Computes the layout of every operation in `iface`, in operation number
+/// order.
+pub fn op_layouts(
+    iface: &syntax::Interface,
+    sizes: &TypeSizes,
+) -> Vec<OpLayout> {
+    iface
+        .ops
+        .iter()
+        .enumerate()
+        .map(|(idx, (name, op))| op_layout(name, idx, op, sizes))
+        .collect()
+}
+
+fn op_layout(
+    name: &str,
+    idx: usize,
+    op: &syntax::Operation,
+    sizes: &TypeSizes,
+) -> OpLayout {
+    let mut offset = Some(0);
+    let mut args = vec![];
+    for (argname, arg) in &op.args {
+        let ty = &arg.repr_ty().0;
+        let size = sizes.size_of(ty);
+        args.push(FieldLayout {
+            name: argname.clone(),
+            ty: ty.clone(),
+            offset,
+            size,
+        });
+        offset = offset.zip(size).map(|(o, s)| o + s);
+    }
+
+    let reply = match &op.reply {
+        syntax::Reply::Result { ok, .. } => {
+            let ty = &ok.repr_ty().0;
+            FieldLayout {
+                name: "ok".to_string(),
+                ty: ty.clone(),
+                offset: Some(0),
+                size: sizes.size_of(ty),
+            }
+        }
+    };
+
+    let leases = op
+        .leases
+        .iter()
+        .map(|(leasename, lease)| {
+            let (elt, _) = lease_element(&lease.ty.0);
+            LeaseLayout {
+                name: leasename.clone(),
+                ty: lease.ty.0.clone(),
+                read: lease.read,
+                write: lease.write,
+                element_size: sizes.size_of(elt),
+                min_len: lease.min_len.map(|n| n.get()),
+                max_len: lease.max_len.map(|n| n.get()),
+            }
+        })
+        .collect();
+
+    OpLayout {
+        name: name.to_string(),
+        number: idx as u16 + 1,
+        args,
+        msg_size: offset,
+        reply,
+        leases,
+    }
+}
+
+/// Formats an optional size, using `?` for unknown.
+struct Size(Option<usize>);
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(n) => write!(f, "{}", n),
+            None => f.write_str("?"),
+        }
+    }
+}
+
+impl fmt::Display for OpLayout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} (operation {})", self.name, self.number)?;
+        writeln!(f, "    message: {} bytes", Size(self.msg_size))?;
+        for arg in &self.args {
+            writeln!(
+                f,
+                "        @{:<4} {}: {} ({} bytes)",
+                Size(arg.offset).to_string(),
+                arg.name,
+                arg.ty,
+                Size(arg.size)
+            )?;
+        }
+        writeln!(
+            f,
+            "    reply: {} ({} bytes)",
+            self.reply.ty,
+            Size(self.reply.size)
+        )?;
+        for (i, lease) in self.leases.iter().enumerate() {
+            write!(
+                f,
+                "    lease {}: {} {} {} ({} bytes per element",
+                i,
+                lease.name,
+                lease.access(),
+                lease.ty,
+                Size(lease.element_size)
+            )?;
+            if let Some(n) = lease.min_len {
+                write!(f, ", at least {} elements", n)?;
+            }
+            if let Some(n) = lease.max_len {
+                write!(f, ", at most {} elements", n)?;
+            }
+            writeln!(f, ")")?;
+        }
+        Ok(())
+    }
+}
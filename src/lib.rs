@@ -4,9 +4,20 @@
 
 pub mod client;
 pub mod common;
+pub mod decode;
+pub mod diff;
+pub mod doc;
+pub mod encode;
+mod error;
+pub mod extract;
+mod generator;
+pub mod layout;
 pub mod server;
 pub mod syntax;
 
+pub use error::Error;
+pub use generator::{ErrorPolicy, Generator, SectionEncoding};
+
 #[cfg(test)]
 mod test {
     const EXAMPLE: &str = r#"
@@ -43,4 +54,446 @@ mod test {
         let _iface = super::syntax::Interface::from_str(EXAMPLE)
             .expect("example failed to parse");
     }
+
+    #[test]
+    fn text_round_trips() {
+        let iface = super::syntax::Interface::from_str(EXAMPLE).unwrap();
+        let text = iface.to_text();
+        let reparsed = super::syntax::Interface::from_str(&text)
+            .expect("canonical text failed to parse");
+        assert_eq!(iface, reparsed);
+        assert!(!text.contains("idempotent"));
+    }
+
+    #[test]
+    fn section_encodings_decode() {
+        use super::{extract, SectionEncoding};
+
+        let iface = super::syntax::Interface::from_str(EXAMPLE).unwrap();
+        for encoding in [SectionEncoding::Source, SectionEncoding::Compact] {
+            let bytes = extract::encode(&iface, EXAMPLE, encoding);
+            let decoded = extract::decode("_SPI_IDOL_DEFINITION", &bytes)
+                .expect("embedded definition failed to decode");
+            assert_eq!(decoded.encoding, encoding);
+            assert_eq!(decoded.interface, iface);
+        }
+    }
+
+    #[test]
+    fn extract_finds_compiled_definitions() {
+        use std::process::Command;
+
+        let iface = super::syntax::Interface::from_str(EXAMPLE).unwrap();
+        let mut src = vec![];
+        super::server::write_server_section(
+            &super::Generator::new(),
+            &iface,
+            EXAMPLE,
+            &mut src,
+        )
+        .unwrap();
+
+        // Build the generated static into a real object, so that it gets the
+        // same symbol name and section placement as it would in a task.
+        let dir = std::env::temp_dir()
+            .join(format!("idol-extract-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let rs = dir.join("probe.rs");
+        let obj = dir.join("probe.o");
+        std::fs::write(&rs, src).unwrap();
+        let rustc = std::env::var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+        let status = Command::new(rustc)
+            .args(["--crate-type=lib", "--emit=obj", "-o"])
+            .arg(&obj)
+            .arg(&rs)
+            .status()
+            .expect("failed to run rustc");
+        assert!(status.success());
+
+        let found = super::extract::extract_file(&obj);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let found = found.expect("extraction failed");
+        assert_eq!(found.len(), 1);
+        assert!(found[0].symbol.contains("_SPI_IDOL_DEFINITION"));
+        assert_eq!(found[0].interface, iface);
+    }
+
+    #[test]
+    fn fingerprint_ignores_formatting() {
+        let iface = super::syntax::Interface::from_str(EXAMPLE).unwrap();
+        let reformatted =
+            super::syntax::Interface::from_str(&iface.to_text()).unwrap();
+        assert_eq!(iface.fingerprint(), reformatted.fingerprint());
+
+        let mut changed = iface.clone();
+        changed.ops.get_mut("lock").unwrap().idempotent = true;
+        assert_ne!(iface.fingerprint(), changed.fingerprint());
+    }
+
+    #[test]
+    fn decode_captured_message() {
+        use super::decode::{self, Reply, Value};
+        use super::layout::TypeSizes;
+
+        let iface = super::syntax::Interface::from_str(EXAMPLE).unwrap();
+        let sizes = TypeSizes::new();
+        let msg =
+            decode::decode_message(&iface, &sizes, 1, &[3], &[12, 4]).unwrap();
+        assert_eq!(msg.args[0].value, Value::Unsigned(3));
+        assert_eq!(
+            msg.to_string(),
+            "exchange { device_index: 3 }\n    source: R [u8; 12]\n    \
+             sink: W [u8; 4]"
+        );
+        assert!(decode::decode_message(&iface, &sizes, 1, &[], &[]).is_err());
+
+        let reply = decode::decode_reply(&iface, &sizes, 2, 2, &[]).unwrap();
+        assert_eq!(reply.to_string(), "Err(SpiError(2))");
+        assert_eq!(
+            decode::decode_reply(&iface, &sizes, 2, 0xFFFF_FE01, &[]).unwrap(),
+            Reply::Rejected("BadMessage")
+        );
+    }
+
+    #[test]
+    fn encoded_calls_decode() {
+        use super::decode::{self, Value};
+        use super::encode;
+        use super::layout::TypeSizes;
+
+        let iface = super::syntax::Interface::from_str(EXAMPLE).unwrap();
+        let sizes = TypeSizes::new();
+        let args = [("device_index".to_string(), "0x2a".to_string())];
+        let call =
+            encode::encode_call_str(&iface, &sizes, "lock", &args).unwrap();
+        assert_eq!((call.number, &call.message[..]), (2, &[42][..]));
+        let msg = decode::decode_message(&iface, &sizes, 2, &call.message, &[])
+            .unwrap();
+        assert_eq!(msg.args[0].value, Value::Unsigned(42));
+
+        let too_big = [("device_index".to_string(), "256".to_string())];
+        assert!(
+            encode::encode_call_str(&iface, &sizes, "lock", &too_big).is_err()
+        );
+    }
+
+    #[test]
+    fn parse_error_has_location() {
+        let err = super::syntax::Interface::from_str("Interface(\n    nam")
+            .unwrap_err();
+        match err {
+            super::Error::Parse { line, .. } => assert_eq!(line, 2),
+            other => panic!("unexpected error: {}", other),
+        }
+    }
+
+    /// Formats an operation named `name` with the given fields, which must
+    /// end with a comma, and a default reply.
+    fn op(name: &str, fields: &str) -> String {
+        format!(
+            r#""{}": ({} reply: Result(ok: "()", err: CLike("E"))),"#,
+            name, fields
+        )
+    }
+
+    #[test]
+    fn diff_classifies_changes() {
+        let a = op("a", "");
+        let b = op("b", "");
+        let lease = |attrs: &str| {
+            op(
+                "a",
+                &format!(r#"leases: {{ "buf": (type: "[u8]", {}) }},"#, attrs),
+            )
+        };
+        let callers = |list: &str| op("a", &format!("{},", list));
+        let arg = |attrs: &str| {
+            op(
+                "a",
+                &format!(r#"args: {{ "x": (type: "u8", {}) }},"#, attrs),
+            )
+        };
+        // Each case is the operations before and after, and whether each
+        // change reported is breaking.
+        let cases: Vec<(String, String, &[bool])> = vec![
+            (a.clone(), a.clone(), &[]),
+            (a.clone(), a.clone() + &b, &[false]),
+            (a.clone() + &b, a.clone(), &[true]),
+            (a.clone(), b.clone() + &a, &[true, false]),
+            (
+                op("a", r#"args: { "x": "u8" },"#),
+                op("a", r#"args: { "x": "u16" },"#),
+                &[true],
+            ),
+            (a.clone(), op("a", "idempotent: true,"), &[true]),
+            (
+                a.clone(),
+                r#""a": (reply: Result(ok: "u8", err: CLike("E"))),"#.into(),
+                &[true],
+            ),
+            (a.clone(), lease("read: true"), &[true]),
+            (
+                lease("read: true"),
+                lease("read: true, write: true"),
+                &[true],
+            ),
+            (
+                lease("read: true, write: true"),
+                lease("read: true"),
+                &[false],
+            ),
+            (
+                lease("read: true"),
+                op("a", r#"leases: { "buf": (type: "[u16]", read: true) },"#),
+                &[true],
+            ),
+            (
+                lease("read: true"),
+                lease("read: true, max_len: Some(4)"),
+                &[true],
+            ),
+            (
+                lease("read: true, max_len: Some(4)"),
+                lease("read: true, max_len: Some(8)"),
+                &[false],
+            ),
+            (
+                lease("read: true, max_len: Some(8)"),
+                lease("read: true, max_len: Some(4)"),
+                &[true],
+            ),
+            (
+                lease("read: true, max_len: Some(4)"),
+                lease("read: true"),
+                &[false],
+            ),
+            (
+                lease("read: true"),
+                lease("read: true, min_len: Some(4)"),
+                &[true],
+            ),
+            (
+                lease("read: true, min_len: Some(4)"),
+                lease("read: true, min_len: Some(8)"),
+                &[true],
+            ),
+            (
+                lease("read: true, min_len: Some(8)"),
+                lease("read: true, min_len: Some(4)"),
+                &[false],
+            ),
+            (
+                lease("read: true, min_len: Some(4)"),
+                lease("read: true"),
+                &[false],
+            ),
+            (a.clone(), callers(r#"allowed_callers: ["a"]"#), &[true]),
+            (callers(r#"allowed_callers: ["a"]"#), a.clone(), &[false]),
+            (
+                callers(r#"allowed_callers: ["a", "b"]"#),
+                callers(r#"allowed_callers: ["a"]"#),
+                &[true],
+            ),
+            (
+                callers(r#"allowed_callers: ["a"]"#),
+                callers(r#"allowed_callers: ["a", "b"]"#),
+                &[false],
+            ),
+            (a.clone(), callers(r#"deny_callers: ["a"]"#), &[true]),
+            (callers(r#"deny_callers: ["a"]"#), a.clone(), &[false]),
+            (
+                callers(r#"allowed_callers: ["a"]"#),
+                callers(r#"deny_callers: ["b"]"#),
+                &[false],
+            ),
+            (
+                callers(r#"allowed_callers: ["a"]"#),
+                callers(r#"deny_callers: ["a"]"#),
+                &[true],
+            ),
+            (
+                callers(r#"deny_callers: ["b"]"#),
+                callers(r#"allowed_callers: ["a"]"#),
+                &[true],
+            ),
+            (arg(""), arg("range: (0, 3)"), &[true]),
+            (arg("range: (0, 3)"), arg(""), &[false]),
+            // Changed constraints are assumed to reject something.
+            (arg("range: (0, 3)"), arg("range: (0, 7)"), &[true]),
+            (arg("one_of: [1, 2]"), arg(r#"validate: "ok""#), &[true]),
+        ];
+
+        let iface = |ops: &str| {
+            super::syntax::Interface::from_str(&format!(
+                r#"Interface(name: "Spi", ops: {{ {} }})"#,
+                ops
+            ))
+            .unwrap()
+        };
+        for (old, new, expected) in cases {
+            let breaking = super::diff::diff(&iface(&old), &iface(&new))
+                .iter()
+                .map(|c| c.is_breaking())
+                .collect::<Vec<_>>();
+            assert_eq!(breaking, expected, "{} -> {}", old, new);
+        }
+    }
+
+    #[test]
+    fn validate_rejects_lease_without_access() {
+        let iface = super::syntax::Interface::from_str(
+            r#"Interface(
+                name: "Bad",
+                ops: {
+                    "op": (
+                        leases: { "buf": (type: "[u8]") },
+                        reply: Result(ok: "()", err: CLike("E")),
+                    ),
+                },
+            )"#,
+        )
+        .unwrap();
+        assert!(matches!(iface.validate(), Err(super::Error::Validation(_))));
+    }
+
+    #[test]
+    fn validate_rejects_allowed_and_denied_callers() {
+        let iface = super::syntax::Interface::from_str(
+            r#"Interface(
+                name: "Bad",
+                ops: {
+                    "op": (
+                        reply: Result(ok: "()", err: CLike("E")),
+                        allowed_callers: ["a"],
+                        deny_callers: ["b"],
+                    ),
+                },
+            )"#,
+        )
+        .unwrap();
+        assert!(matches!(iface.validate(), Err(super::Error::Validation(_))));
+    }
+
+    #[test]
+    fn validate_rejects_writable_str_leases() {
+        let text = |access: &str| {
+            format!(
+                r#"Interface(
+                    name: "Log",
+                    ops: {{
+                        "write": (
+                            leases: {{
+                                "text": (type: "str", {}, max_len: Some(8)),
+                            }},
+                            reply: Result(ok: "()", err: CLike("E")),
+                        ),
+                    }},
+                )"#,
+                access
+            )
+        };
+        let iface =
+            super::syntax::Interface::from_str(&text("read: true")).unwrap();
+        assert!(iface.validate().is_ok());
+        let iface =
+            super::syntax::Interface::from_str(&text("write: true")).unwrap();
+        assert!(matches!(iface.validate(), Err(super::Error::Validation(_))));
+    }
+
+    #[test]
+    fn bounded_replies_decode() {
+        use super::decode::{self, Reply, Value};
+        use super::layout::TypeSizes;
+
+        let iface = super::syntax::Interface::from_str(
+            r#"Interface(
+                name: "Id",
+                ops: {
+                    "read": (
+                        reply: Result(ok: "[u16; ..4]", err: CLike("E")),
+                    ),
+                },
+            )"#,
+        )
+        .unwrap();
+        assert!(iface.validate().is_ok());
+        let sizes = TypeSizes::new();
+        assert_eq!(
+            decode::decode_reply(&iface, &sizes, 1, 0, &[1, 0, 2, 0]).unwrap(),
+            Reply::Ok(Value::Array(vec![
+                Value::Unsigned(1),
+                Value::Unsigned(2)
+            ]))
+        );
+        assert!(decode::decode_reply(&iface, &sizes, 1, 0, &[1, 0, 2]).is_err());
+        assert!(decode::decode_reply(&iface, &sizes, 1, 0, &[0; 10]).is_err());
+    }
+
+    #[test]
+    fn arg_constraints_round_trip_and_validate() {
+        let text = |range: &str| {
+            format!(
+                r#"Interface(
+                    name: "Spi",
+                    ops: {{
+                        "lock": (
+                            args: {{
+                                "device_index": (type: "u8", range: {}),
+                                "mode": (type: "u8", one_of: [1, 4], validate: "ok"),
+                            }},
+                            reply: Result(ok: "()", err: CLike("E")),
+                        ),
+                    }},
+                )"#,
+                range
+            )
+        };
+        let iface =
+            super::syntax::Interface::from_str(&text("(0, 3)")).unwrap();
+        iface.validate().unwrap();
+        let reparsed =
+            super::syntax::Interface::from_str(&iface.to_text()).unwrap();
+        assert_eq!(iface, reparsed);
+
+        // Bounds must fit in the argument's type.
+        for range in ["(-1, 3)", "(0, 300)"] {
+            let iface =
+                super::syntax::Interface::from_str(&text(range)).unwrap();
+            assert!(matches!(
+                iface.validate(),
+                Err(super::Error::Validation(_))
+            ));
+        }
+        let iface = super::syntax::Interface::from_str(
+            &text("(0, 3)").replace("[1, 4]", "[1, 256]"),
+        )
+        .unwrap();
+        assert!(matches!(iface.validate(), Err(super::Error::Validation(_))));
+    }
+
+    #[test]
+    fn generator_uses_crate_paths() {
+        let iface = super::syntax::Interface::from_str(EXAMPLE).unwrap();
+        let mut out = vec![];
+        super::Generator::new()
+            .userlib_path("facade::userlib")
+            .generate_client_stub(&iface, &mut out)
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("facade::userlib::sys_send("));
+        assert!(!out.contains(" userlib::"));
+    }
+
+    #[test]
+    fn generator_wraps_module() {
+        let iface = super::syntax::Interface::from_str(EXAMPLE).unwrap();
+        let mut out = vec![];
+        super::Generator::new()
+            .module("spi")
+            .generate_client_stub(&iface, &mut out)
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("pub mod spi {"));
+        assert!(out.trim_end().ends_with('}'));
+    }
 }
@@ -2,47 +2,94 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use super::{common, syntax};
-use std::env;
-use std::fs::File;
+use super::{common, extract, layout, syntax, Error, Generator};
 use std::io::Write;
-use std::path::PathBuf;
 
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum ServerStyle {
     InOrder,
 }
 
+impl std::str::FromStr for ServerStyle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "InOrder" => Ok(Self::InOrder),
+            _ => Err(format!("unknown server style `{}`", s)),
+        }
+    }
+}
+
 pub fn build_server_support(
     source: &str,
     stub_name: &str,
     style: ServerStyle,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
-    let mut stub_file = File::create(out.join(stub_name)).unwrap();
+) -> Result<(), Error> {
+    Generator::new()
+        .server_style(style)
+        .build_server_support(source, stub_name)
+}
 
-    let text = std::fs::read_to_string(source)?;
-    let iface: syntax::Interface = ron::de::from_str(&text)?;
+/// Generates the complete server support code for `iface`, as produced by
+/// `build_server_support`.
+///
+/// `text` is the source text the interface was parsed from; it gets embedded
+/// in the `.idolatry` section of the output.
+pub fn generate_server_support(
+    iface: &syntax::Interface,
+    text: &str,
+    style: ServerStyle,
+    out: impl Write,
+) -> Result<(), Error> {
+    Generator::new()
+        .server_style(style)
+        .generate_server_support(iface, text, out)
+}
 
-    generate_server_constants(&iface, &mut stub_file)?;
-    generate_server_conversions(&iface, &mut stub_file)?;
-    common::generate_op_enum(&iface, &mut stub_file)?;
-    generate_server_op_impl(&iface, &mut stub_file)?;
+pub(crate) fn write_server_support(
+    gen: &Generator,
+    iface: &syntax::Interface,
+    text: &str,
+    mut out: impl Write,
+) -> Result<(), Error> {
+    write_server_constants(iface, &mut out)?;
+    write_server_conversions(gen, iface, &mut out)?;
+    common::write_op_enum(gen, iface, &mut out)?;
+    write_server_op_impl(gen, iface, &mut out)?;
 
-    match style {
+    match gen.server_style {
         ServerStyle::InOrder => {
-            generate_server_in_order_trait(&iface, &mut stub_file)?;
+            write_server_in_order_trait(gen, iface, &mut out)?;
         }
     }
 
-    generate_server_section(&iface, &text, &mut stub_file)?;
-    println!("cargo:rerun-if-changed={}", source);
+    if gen.emit_section {
+        write_server_section(gen, iface, text, &mut out)?;
+    }
+    if gen.reflection {
+        write_server_reflection(gen, iface, text, &mut out)?;
+    }
+    if gen.fuzz_harness {
+        write_server_fuzz_harness(gen, iface, &mut out)?;
+    }
+    if gen.counters {
+        write_server_counters(gen, iface, &mut out)?;
+    }
     Ok(())
 }
 
 pub fn generate_server_constants(
+    iface: &syntax::Interface,
+    out: impl Write,
+) -> Result<(), Error> {
+    write_server_constants(iface, out)
+}
+
+fn write_server_constants(
     iface: &syntax::Interface,
     mut out: impl Write,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<(), Error> {
     // Generate message sizing constants for each message.
     let mut upper_names = vec![];
     for (name, op) in &iface.ops {
@@ -51,7 +98,7 @@ pub fn generate_server_constants(
         writeln!(out, "pub const {}_MSG_SIZE: usize = 0", upper_name)?;
 
         for arg in op.args.values() {
-            writeln!(out, "    + core::mem::size_of::<{}>()", arg.ty.0)?;
+            writeln!(out, "    + ::core::mem::size_of::<{}>()", arg.ty.0)?;
         }
         writeln!(out, "    ;")?;
 
@@ -60,7 +107,11 @@ pub fn generate_server_constants(
             syntax::Reply::Result { ok, .. } => {
                 // This strategy only uses bytes for the OK side of the type,
                 // and only sends one type, so:
-                writeln!(out, "core::mem::size_of::<{}>();", ok.display())?;
+                writeln!(
+                    out,
+                    " ::core::mem::size_of::<{}>();",
+                    common::reply_size_ty(ok)
+                )?;
             }
         }
 
@@ -81,16 +132,26 @@ pub fn generate_server_constants(
 }
 
 pub fn generate_server_conversions(
+    iface: &syntax::Interface,
+    out: impl Write,
+) -> Result<(), Error> {
+    iface.validate()?;
+    write_server_conversions(&Generator::new(), iface, out)
+}
+
+fn write_server_conversions(
+    gen: &Generator,
     iface: &syntax::Interface,
     mut out: impl Write,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<(), Error> {
     for (name, op) in &iface.ops {
         // Define args struct.
         writeln!(out, "#[allow(non_camel_case_types)]")?;
         writeln!(out, "#[repr(C, packed)]")?;
         writeln!(
             out,
-            "#[derive(Copy, Clone, zerocopy::FromBytes, zerocopy::Unaligned)]"
+            "#[derive(Copy, Clone, {0}::FromBytes, {0}::Unaligned)]",
+            gen.zerocopy
         )?;
         writeln!(out, "pub struct {}_{}_ARGS {{", iface.name, name)?;
         let mut need_args_impl = false;
@@ -112,21 +173,18 @@ pub fn generate_server_conversions(
         if need_args_impl {
             writeln!(out, "impl {}_{}_ARGS {{", iface.name, name)?;
             for (argname, arg) in &op.args {
-                match &arg.recv {
-                    syntax::RecvStrategy::FromPrimitive(ty) => {
-                        writeln!(
-                            out,
-                            "    pub fn {}(&self) -> Option<{}> {{",
-                            argname, arg.ty.0
-                        )?;
-                        writeln!(
-                            out,
-                            "        userlib::FromPrimitive::from_{}(self.raw_{})",
-                            ty.0, argname
-                        )?;
-                        writeln!(out, "    }}")?;
-                    }
-                    _ => (),
+                if let syntax::RecvStrategy::FromPrimitive(ty) = &arg.recv {
+                    writeln!(
+                        out,
+                        "    pub fn {}(&self) -> Option<{}> {{",
+                        argname, arg.ty.0
+                    )?;
+                    writeln!(
+                        out,
+                        "        {}::FromPrimitive::from_{}(self.raw_{})",
+                        gen.userlib, ty.0, argname
+                    )?;
+                    writeln!(out, "    }}")?;
                 }
             }
             writeln!(out, "}}")?;
@@ -135,27 +193,90 @@ pub fn generate_server_conversions(
         writeln!(out, "pub fn read_{}_msg(bytes: &[u8])", name)?;
         writeln!(out, "    -> Option<&{}_{}_ARGS>", iface.name, name)?;
         writeln!(out, "{{")?;
-        writeln!(
-            out,
-            "    Some(zerocopy::LayoutVerified::<_, {}_{}_ARGS>::new_unaligned(bytes)?",
-            iface.name, name
-        )?;
-        writeln!(out, "        .into_ref())")?;
+        if op.args.values().any(|arg| arg.is_constrained()) {
+            writeln!(
+                out,
+                "    let args = {}::LayoutVerified::<_, {}_{}_ARGS>::new_unaligned(bytes)?",
+                gen.zerocopy, iface.name, name
+            )?;
+            writeln!(out, "        .into_ref();")?;
+            for (argname, arg) in &op.args {
+                write_server_arg_check(argname, arg, &mut out)?;
+            }
+            writeln!(out, "    Some(args)")?;
+        } else {
+            writeln!(
+                out,
+                "    Some({}::LayoutVerified::<_, {}_{}_ARGS>::new_unaligned(bytes)?",
+                gen.zerocopy, iface.name, name
+            )?;
+            writeln!(out, "        .into_ref())")?;
+        }
         writeln!(out, "}}")?;
     }
 
     Ok(())
 }
 
-fn generate_server_op_impl(
+/// Writes checks of `arg` against its constraints, if it has any, into the
+/// body of `read_<op>_msg`.
+fn write_server_arg_check(
+    argname: &str,
+    arg: &syntax::AttributedTy,
+    mut out: impl Write,
+) -> Result<(), Error> {
+    if !arg.is_constrained() {
+        return Ok(());
+    }
+    // Copy the value out, since fields of packed structs can't be borrowed.
+    let field = match &arg.recv {
+        syntax::RecvStrategy::FromBytes => argname.to_string(),
+        _ => format!("raw_{}", argname),
+    };
+    writeln!(out, "    let {} = args.{};", argname, field)?;
+    if let Some((lo, hi)) = arg.range {
+        writeln!(out, "    if !({}..={}).contains(&{}) {{", lo, hi, argname)?;
+        writeln!(out, "        return None;")?;
+        writeln!(out, "    }}")?;
+    }
+    if !arg.one_of.is_empty() {
+        writeln!(out, "    if !{:?}.contains(&{}) {{", arg.one_of, argname)?;
+        writeln!(out, "        return None;")?;
+        writeln!(out, "    }}")?;
+    }
+    if let Some(f) = &arg.validate {
+        writeln!(out, "    if !{}(&{}) {{", f, argname)?;
+        writeln!(out, "        return None;")?;
+        writeln!(out, "    }}")?;
+    }
+    Ok(())
+}
+
+fn write_server_op_impl(
+    gen: &Generator,
     iface: &syntax::Interface,
     mut out: impl Write,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<(), Error> {
     writeln!(
         out,
-        "impl idol_runtime::ServerOp for {}Operation {{",
-        iface.name
+        "impl {}::ServerOp for {}Operation {{",
+        gen.idol_runtime, iface.name
     )?;
+    if gen.handshake {
+        writeln!(
+            out,
+            "    const HANDSHAKE: Option<u64> = Some(Self::FINGERPRINT);"
+        )?;
+        writeln!(out)?;
+    }
+    if gen.reflection {
+        writeln!(
+            out,
+            "    const DEFINITION: Option<&'static [u8]> = Some(_{}_IDOL_REFLECTION);",
+            iface.name.to_uppercase()
+        )?;
+        writeln!(out)?;
+    }
 
     writeln!(out, "    fn max_reply_size(&self) -> usize {{")?;
     writeln!(out, "        match self {{")?;
@@ -175,12 +296,7 @@ fn generate_server_op_impl(
     writeln!(out, "        match self {{")?;
     // Note: if we start allowing optional leases this will have to get fancier.
     for (opname, op) in &iface.ops {
-        writeln!(
-            out,
-            "            Self::{} => {},",
-            opname,
-            op.leases.len(),
-        )?;
+        writeln!(out, "            Self::{} => {},", opname, op.leases.len(),)?;
     }
     writeln!(out, "        }}")?;
     writeln!(out, "    }}")?;
@@ -192,15 +308,38 @@ fn generate_server_op_impl(
 }
 
 pub fn generate_server_in_order_trait(
+    iface: &syntax::Interface,
+    out: impl Write,
+) -> Result<(), Error> {
+    iface.validate()?;
+    write_server_in_order_trait(&Generator::new(), iface, out)
+}
+
+fn write_server_in_order_trait(
+    gen: &Generator,
     iface: &syntax::Interface,
     mut out: impl Write,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<(), Error> {
     let trt = format!("InOrder{}Impl", iface.name);
+    let rt = &gen.idol_runtime;
 
+    if gen.docs {
+        writeln!(
+            out,
+            "/// Implementation of the `{}` interface for servers that reply to",
+            iface.name
+        )?;
+        writeln!(
+            out,
+            "/// each message before receiving the next, for use with"
+        )?;
+        writeln!(out, "/// `{}::dispatch`.", rt)?;
+    }
     writeln!(out, "pub trait {} {{", trt)?;
     writeln!(
         out,
-        "    fn recv_source(&self) -> Option<userlib::TaskId> {{"
+        "    fn recv_source(&self) -> Option<{}::TaskId> {{",
+        gen.userlib
     )?;
     writeln!(out, "        None")?;
     writeln!(out, "    }}")?;
@@ -209,66 +348,48 @@ pub fn generate_server_in_order_trait(
     writeln!(out, "        panic!()")?;
     writeln!(out, "    }}")?;
     writeln!(out)?;
-    for (name, op) in &iface.ops {
-        writeln!(out, "    fn {}(", name)?;
-        writeln!(out, "        &mut self,")?;
-        writeln!(out, "        msg: &userlib::RecvMessage,")?;
-        for (argname, arg) in &op.args {
-            writeln!(out, "        {}: {},", argname, arg.ty.0)?;
-        }
-        for (leasename, lease) in &op.leases {
-            if let Some(n) = &lease.max_len {
-                write!(out, "        {}: idol_runtime::LenLimit<idol_runtime::Leased<idol_runtime::", leasename)?;
-                if lease.read {
-                    write!(out, "R")?;
-                }
-                if lease.write {
-                    write!(out, "W")?;
-                }
-                writeln!(out, ", {}>, {}>,", lease.ty.0, n)?;
-            } else {
-                write!(
-                    out,
-                    "        {}: idol_runtime::Leased<idol_runtime::",
-                    leasename
-                )?;
-                if lease.read {
-                    write!(out, "R")?;
-                }
-                if lease.write {
-                    write!(out, "W")?;
-                }
-                writeln!(out, ", {}>,", lease.ty.0)?;
-            }
+    if iface.ops.values().any(|op| op.restricts_callers()) {
+        if gen.docs {
+            writeln!(
+                out,
+                "    /// Checks whether `sender` is the task named `task`, to \
+                 enforce the"
+            )?;
+            writeln!(
+                out,
+                "    /// `allowed_callers` and `deny_callers` of operations."
+            )?;
         }
-        write!(out, ")")?;
-
-        match &op.reply {
-            syntax::Reply::Result { ok, err } => {
-                write!(
-                    out,
-                    " -> Result<{}, idol_runtime::RequestError<",
-                    ok.display()
-                )?;
-                match err {
-                    syntax::Error::CLike(ty) => {
-                        write!(out, "{}", ty.0)?;
-                    }
-                }
-                write!(out, ">>")?;
-            }
+        writeln!(
+            out,
+            "    fn sender_is_task(&self, sender: {}::TaskId, task: &str) -> bool;",
+            gen.userlib
+        )?;
+        writeln!(out)?;
+    }
+    for (idx, (name, op)) in iface.ops.iter().enumerate() {
+        if gen.docs {
+            writeln!(
+                out,
+                "    /// Handles `{}.{}` (operation {}).",
+                iface.name,
+                name,
+                idx + 1
+            )?;
         }
+        write_server_op_signature(gen, name, op, "", &mut out)?;
         writeln!(out, ";")?;
         writeln!(out)?;
     }
     writeln!(out, "}}")?;
     writeln!(out)?;
 
-    writeln!(out, "impl<S: {}> idol_runtime::Server<{}Operation> for (core::marker::PhantomData<{1}Operation>, &'_ mut S) {{", trt, iface.name)?;
+    writeln!(out, "impl<S: {}> {}::Server<{}Operation> for (::core::marker::PhantomData<{2}Operation>, &'_ mut S) {{", trt, rt, iface.name)?;
 
     writeln!(
         out,
-        "    fn recv_source(&self) -> Option<userlib::TaskId> {{"
+        "    fn recv_source(&self) -> Option<{}::TaskId> {{",
+        gen.userlib
     )?;
     writeln!(out, "        <S as {}>::recv_source(self.1)", trt)?;
     writeln!(out, "    }}")?;
@@ -281,14 +402,15 @@ pub fn generate_server_in_order_trait(
     writeln!(out, "        &mut self,")?;
     writeln!(out, "        op: {}Operation,", iface.name)?;
     writeln!(out, "        incoming: &[u8],")?;
-    writeln!(out, "        rm: &userlib::RecvMessage,")?;
+    writeln!(out, "        rm: &{}::RecvMessage,", gen.userlib)?;
     writeln!(out, "    ) -> Result<(), u32> {{")?;
     writeln!(out, "        #[allow(unused_imports)]")?;
-    writeln!(out, "        use core::convert::TryInto;")?;
-    writeln!(out, "        use idol_runtime::ClientError;")?;
+    writeln!(out, "        use ::core::convert::TryInto;")?;
+    writeln!(out, "        use {}::ClientError;", rt)?;
     writeln!(out, "        match op {{")?;
     for (opname, op) in &iface.ops {
         writeln!(out, "            {}Operation::{} => {{", iface.name, opname)?;
+        write_server_caller_check(op, &mut out)?;
         writeln!(
             out,
             "                let {}args = read_{}_msg(incoming).ok_or(ClientError::BadMessage)?;",
@@ -335,38 +457,55 @@ pub fn generate_server_in_order_trait(
             };
 
             let (suffix, limit) = if lease.ty.appears_unsized() {
-                let max_len = if let Some(n) = lease.max_len {
-                    // It's ok to unwrap the value in server code because we've
-                    // just gotten it _out of_ a NonZeroU32 here, so we know
-                    // it'll be statically valid.
-                    format!(
-                        ", Some(core::num::NonZeroU32::new({}).unwrap())",
+                let suffix = if lease.ty.is_str() { "_str" } else { "_slice" };
+                // It's ok to unwrap the values in server code because we've
+                // just gotten them _out of_ a NonZeroU32 here, so we know
+                // they'll be statically valid.
+                let len = |n: Option<std::num::NonZeroU32>| match n {
+                    Some(n) => format!(
+                        "Some(::core::num::NonZeroU32::new({}).unwrap())",
                         n
-                    )
-                } else {
-                    ", None".to_string()
+                    ),
+                    None => "None".to_string(),
                 };
-                ("_slice", max_len)
+                (
+                    suffix,
+                    format!(", {}, {}", len(lease.min_len), len(lease.max_len)),
+                )
             } else {
-                if lease.max_len.is_some() {
-                    panic!("Lease {} on operation {}.{} has sized type but also max_len field",
-                        i, iface.name, opname);
-                }
                 ("", "".to_string())
             };
 
-            write!(out, "                    idol_runtime::Leased::{}{}(rm.sender, {}{}).ok_or(ClientError::BadLease)?", fun, suffix, i, limit)?;
-            if lease.max_len.is_some() {
-                write!(out, ".try_into().unwrap()")?;
-            }
-            writeln!(out, ",")?;
+            let leased = format!(
+                "{}::Leased::{}{}(rm.sender, {}{}).map_err(ClientError::from)?",
+                rt, fun, suffix, i, limit
+            );
+            // The constructor has checked the lengths, so the conversions to
+            // the wrapper types can't fail.
+            let arg = match lease_types(gen, lease) {
+                (_, Some(limited), Some(_)) => format!(
+                    "::core::convert::TryInto::<{}>::try_into({}).unwrap().try_into().unwrap()",
+                    limited, leased
+                ),
+                (_, Some(_), None) | (_, None, Some(_)) => {
+                    format!("{}.try_into().unwrap()", leased)
+                }
+                (_, None, None) => leased,
+            };
+            writeln!(out, "                    {},", arg)?;
         }
         writeln!(out, "                );")?;
         match &op.reply {
-            syntax::Reply::Result { err, .. } => {
+            syntax::Reply::Result { ok, err } => {
+                // Bounded replies send only the values present.
+                let val = if ok.ty.bounded().is_some() {
+                    "val.as_slice()"
+                } else {
+                    "&val"
+                };
                 writeln!(out, "                match r {{")?;
                 writeln!(out, "                    Ok(val) => {{")?;
-                writeln!(out, "                        userlib::sys_reply(rm.sender, 0, zerocopy::AsBytes::as_bytes(&val));")?;
+                writeln!(out, "                        {}::sys_reply(rm.sender, 0, {}::AsBytes::as_bytes({}));", gen.userlib, gen.zerocopy, val)?;
                 writeln!(out, "                        Ok(())")?;
                 writeln!(out, "                    }}")?;
                 writeln!(out, "                    Err(val) => {{")?;
@@ -396,12 +535,344 @@ pub fn generate_server_in_order_trait(
     Ok(())
 }
 
-fn generate_server_section(
+/// Writes the check of the sender against the `allowed_callers` or
+/// `deny_callers` of `op`, if it has either, into the body of `handle`.
+fn write_server_caller_check(
+    op: &syntax::Operation,
+    mut out: impl Write,
+) -> Result<(), Error> {
+    let (names, negate) = if !op.allowed_callers.is_empty() {
+        (&op.allowed_callers, "!")
+    } else if !op.deny_callers.is_empty() {
+        (&op.deny_callers, "")
+    } else {
+        return Ok(());
+    };
+    writeln!(out, "                if {}{:?}", negate, names)?;
+    writeln!(out, "                    .iter()")?;
+    writeln!(
+        out,
+        "                    .any(|t| self.1.sender_is_task(rm.sender, t))"
+    )?;
+    writeln!(out, "                {{")?;
+    writeln!(
+        out,
+        "                    return Err(ClientError::AccessDenied.into());"
+    )?;
+    writeln!(out, "                }}")?;
+    Ok(())
+}
+
+/// Returns the types that the server passes `lease` as: the `Leased` type,
+/// then, if the lease has a `max_len`, that wrapped in a `LenLimit`, and then,
+/// if the lease has a `min_len`, the last of those wrapped in a `MinLen`. The
+/// last type given is the one the server implementation receives.
+///
+/// `str` leases aren't wrapped, as the wrappers only apply to slices; the
+/// lengths are still checked when the `Leased` is created.
+fn lease_types(
+    gen: &Generator,
+    lease: &syntax::Lease,
+) -> (String, Option<String>, Option<String>) {
+    let rt = &gen.idol_runtime;
+    let access = layout::access_name(lease.read, lease.write);
+    let leased = format!("{0}::Leased<{0}::{1}, {2}>", rt, access, lease.ty.0);
+    if lease.ty.is_str() {
+        return (leased, None, None);
+    }
+    let limited = lease
+        .max_len
+        .map(|n| format!("{}::LenLimit<{}, {}>", rt, leased, n));
+    let full = lease.min_len.map(|n| {
+        format!(
+            "{}::MinLen<{}, {}>",
+            rt,
+            limited.as_ref().unwrap_or(&leased),
+            n
+        )
+    });
+    (leased, limited, full)
+}
+
+/// Writes the signature of the `InOrder` trait method for `op`, up to but not
+/// including the body or semicolon. `prefix` goes in front of each parameter
+/// name, so that implementations can mark them unused.
+fn write_server_op_signature(
+    gen: &Generator,
+    name: &str,
+    op: &syntax::Operation,
+    prefix: &str,
+    mut out: impl Write,
+) -> Result<(), Error> {
+    let rt = &gen.idol_runtime;
+    writeln!(out, "    fn {}(", name)?;
+    writeln!(out, "        &mut self,")?;
+    writeln!(out, "        {}msg: &{}::RecvMessage,", prefix, gen.userlib)?;
+    for (argname, arg) in &op.args {
+        writeln!(out, "        {}{}: {},", prefix, argname, arg.ty.0)?;
+    }
+    for (leasename, lease) in &op.leases {
+        let (leased, limited, full) = lease_types(gen, lease);
+        writeln!(
+            out,
+            "        {}{}: {},",
+            prefix,
+            leasename,
+            full.or(limited).unwrap_or(leased)
+        )?;
+    }
+    write!(out, "    )")?;
+
+    match &op.reply {
+        syntax::Reply::Result { ok, err } => {
+            write!(
+                out,
+                " -> Result<{}, {}::RequestError<",
+                common::reply_ty(gen, ok),
+                rt
+            )?;
+            match err {
+                syntax::Error::CLike(ty) => {
+                    write!(out, "{}", ty.0)?;
+                }
+            }
+            write!(out, ">>")?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes a server for fuzzing the generated decoding code with
+/// `idol_runtime::fuzz::run`, along with the shapes of the interface's
+/// messages and a `fuzz_<name>` function tying them together.
+fn write_server_fuzz_harness(
+    gen: &Generator,
+    iface: &syntax::Interface,
+    mut out: impl Write,
+) -> Result<(), Error> {
+    let rt = &gen.idol_runtime;
+    let server = format!("Fuzz{}Server", iface.name);
+    let shapes = format!("{}_FUZZ_SHAPES", iface.name.to_uppercase());
+
+    writeln!(
+        out,
+        "/// Server whose every operation fails with `ClientError::WentAway`, \
+         for use"
+    )?;
+    writeln!(out, "/// with `{}::fuzz::run`.", rt)?;
+    writeln!(out, "pub struct {};", server)?;
+    writeln!(out)?;
+    writeln!(out, "impl InOrder{}Impl for {} {{", iface.name, server)?;
+    if iface.ops.values().any(|op| op.restricts_callers()) {
+        // Let every message through to the decoding code: the sender counts
+        // as any task that isn't denied outright.
+        let denied = iface
+            .ops
+            .values()
+            .flat_map(|op| &op.deny_callers)
+            .collect::<Vec<_>>();
+        writeln!(
+            out,
+            "    fn sender_is_task(&self, _: {}::TaskId, task: &str) -> bool {{",
+            gen.userlib
+        )?;
+        writeln!(out, "        !{:?}.contains(&task)", denied)?;
+        writeln!(out, "    }}")?;
+        writeln!(out)?;
+    }
+    for (name, op) in &iface.ops {
+        write_server_op_signature(gen, name, op, "_", &mut out)?;
+        writeln!(out, " {{")?;
+        writeln!(out, "        Err({}::RequestError::went_away())", rt)?;
+        writeln!(out, "    }}")?;
+        writeln!(out)?;
+    }
+    writeln!(out, "}}")?;
+    writeln!(out)?;
+
+    writeln!(
+        out,
+        "/// Shapes of the messages for each operation of `{}`, in order.",
+        iface.name
+    )?;
+    writeln!(out, "pub const {}: &[{}::fuzz::OpShape] = &[", shapes, rt)?;
+    for (name, op) in &iface.ops {
+        let fallible = op.args.values().any(|a| {
+            a.is_constrained()
+                || matches!(a.recv, syntax::RecvStrategy::FromPrimitive(_))
+        });
+        writeln!(out, "    {}::fuzz::OpShape {{", rt)?;
+        writeln!(
+            out,
+            "        message_size: {}_MSG_SIZE,",
+            name.to_uppercase()
+        )?;
+        writeln!(
+            out,
+            "        reply_size: {}_REPLY_SIZE,",
+            name.to_uppercase()
+        )?;
+        writeln!(out, "        fallible_args: {},", fallible)?;
+        writeln!(out, "        leases: &[")?;
+        for lease in op.leases.values() {
+            let (element, slice) = layout::lease_element(&lease.ty.0);
+            writeln!(out, "            {}::fuzz::LeaseShape {{", rt)?;
+            writeln!(out, "                read: {},", lease.read)?;
+            writeln!(out, "                write: {},", lease.write)?;
+            writeln!(
+                out,
+                "                element_size: ::core::mem::size_of::<{}>(),",
+                element
+            )?;
+            writeln!(out, "                slice: {},", slice)?;
+            writeln!(
+                out,
+                "                min_len: {:?},",
+                lease.min_len.map(|n| n.get())
+            )?;
+            writeln!(
+                out,
+                "                max_len: {:?},",
+                lease.max_len.map(|n| n.get())
+            )?;
+            writeln!(out, "            }},")?;
+        }
+        writeln!(out, "        ],")?;
+        writeln!(out, "    }},")?;
+    }
+    writeln!(out, "];")?;
+    writeln!(out)?;
+
+    writeln!(
+        out,
+        "/// Sends a message decoded from `data` to a `{}`, panicking if it's",
+        server
+    )?;
+    writeln!(out, "/// mishandled. Call this from a fuzz target.")?;
+    writeln!(
+        out,
+        "pub fn fuzz_{}(data: &[u8]) {{",
+        iface.name.to_lowercase()
+    )?;
+    writeln!(out, "    {}::fuzz::run(data, {}, || {{", rt, shapes)?;
+    writeln!(out, "        let mut buffer = [0; INCOMING_SIZE];")?;
+    writeln!(out, "        loop {{")?;
+    writeln!(
+        out,
+        "            {}::dispatch(&mut buffer, &mut {});",
+        rt, server
+    )?;
+    writeln!(out, "        }}")?;
+    writeln!(out, "    }});")?;
+    writeln!(out, "}}")?;
+    writeln!(out)?;
+    Ok(())
+}
+
+/// Writes `<Name>Counters`, a `DispatchObserver` that counts what the
+/// dispatch routines see, and a `static` instance for servers to pass to
+/// `dispatch_observed`.
+fn write_server_counters(
+    gen: &Generator,
+    iface: &syntax::Interface,
+    mut out: impl Write,
+) -> Result<(), Error> {
+    let rt = &gen.idol_runtime;
+    let counters = format!("{}Counters", iface.name);
+
+    writeln!(
+        out,
+        "/// Counts of the messages received by a `{}` server, by operation.",
+        iface.name
+    )?;
+    writeln!(out, "#[derive(Debug, Default)]")?;
+    writeln!(out, "#[repr(C)]")?;
+    writeln!(out, "pub struct {} {{", counters)?;
+    for name in iface.ops.keys() {
+        writeln!(out, "    pub {}: {}::OpCounters,", name, rt)?;
+    }
+    writeln!(out, "    pub rejected: {}::ClientErrorCounters,", rt)?;
+    writeln!(out, "    pub notifications: {}::Counter,", rt)?;
+    writeln!(out, "}}")?;
+    writeln!(out)?;
+
+    writeln!(out, "impl {} {{", counters)?;
+    writeln!(out, "    pub const fn new() -> Self {{")?;
+    writeln!(out, "        Self {{")?;
+    for name in iface.ops.keys() {
+        writeln!(out, "            {}: {}::OpCounters::new(),", name, rt)?;
+    }
+    writeln!(
+        out,
+        "            rejected: {}::ClientErrorCounters::new(),",
+        rt
+    )?;
+    writeln!(out, "            notifications: {}::Counter::new(),", rt)?;
+    writeln!(out, "        }}")?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "}}")?;
+    writeln!(out)?;
+
+    writeln!(
+        out,
+        "impl {}::DispatchObserver<{}Operation> for {} {{",
+        rt, iface.name, counters
+    )?;
+    writeln!(
+        out,
+        "    fn on_reject(&self, _: &{}::RecvMessage, err: {}::ClientError) {{",
+        gen.userlib, rt
+    )?;
+    writeln!(out, "        self.rejected.count(err);")?;
+    writeln!(out, "    }}")?;
+    writeln!(out)?;
+    writeln!(
+        out,
+        "    fn on_handled(&self, op: {}Operation, rc: u32) {{",
+        iface.name
+    )?;
+    writeln!(out, "        let counters = match op {{")?;
+    for name in iface.ops.keys() {
+        writeln!(
+            out,
+            "            {}Operation::{} => &self.{},",
+            iface.name, name, name
+        )?;
+    }
+    writeln!(out, "        }};")?;
+    writeln!(out, "        counters.count(rc);")?;
+    writeln!(out, "    }}")?;
+    writeln!(out)?;
+    writeln!(out, "    fn on_notification(&self, _: u32) {{")?;
+    writeln!(out, "        self.notifications.count();")?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "}}")?;
+    writeln!(out)?;
+
+    writeln!(
+        out,
+        "/// Counters for this task's `{}` server, to pass to `{}::dispatch_observed`.",
+        iface.name, rt
+    )?;
+    writeln!(out, "#[used]")?;
+    writeln!(
+        out,
+        "pub static {}_COUNTERS: {} = {}::new();",
+        iface.name.to_uppercase(),
+        counters,
+        counters
+    )?;
+    writeln!(out)?;
+    Ok(())
+}
+
+pub(crate) fn write_server_section(
+    gen: &Generator,
     iface: &syntax::Interface,
     text: &str,
     mut out: impl Write,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let bytes = text.as_bytes();
+) -> Result<(), Error> {
+    let bytes = extract::encode(iface, text, gen.section_encoding);
 
     write!(
         out,
@@ -412,12 +883,37 @@ fn generate_server_section(
 #[link_section = ".idolatry"]
 static _{}_IDOL_DEFINITION: [u8; {}] = ["##,
         iface.name.to_uppercase(),
-        text.len()
+        bytes.len()
+    )?;
+
+    write_byte_array(&bytes, out)
+}
+
+fn write_server_reflection(
+    gen: &Generator,
+    iface: &syntax::Interface,
+    text: &str,
+    mut out: impl Write,
+) -> Result<(), Error> {
+    let bytes = extract::encode(iface, text, gen.section_encoding);
+
+    write!(
+        out,
+        r##"
+// Copy of the interface definition sent in reply to the reflection operation.
+// The copy in .idolatry isn't loaded into memory, so we can't use that.
+const _{}_IDOL_REFLECTION: &[u8] = &["##,
+        iface.name.to_uppercase()
     )?;
 
-    for i in 0..bytes.len() {
+    write_byte_array(&bytes, out)
+}
+
+/// Writes the elements and closing bracket of a byte array literal.
+fn write_byte_array(bytes: &[u8], mut out: impl Write) -> Result<(), Error> {
+    for (i, b) in bytes.iter().enumerate() {
         let delim = if i % 10 == 0 { "\n    " } else { " " };
-        write!(out, "{}0x{:02x},", delim, bytes[i])?;
+        write!(out, "{}0x{:02x},", delim, b)?;
     }
 
     writeln!(out, "\n];\n")?;
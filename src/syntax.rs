@@ -10,9 +10,23 @@
 use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
 use std::num::NonZeroU32;
+use std::path::Path;
+
+/// Operation numbers from here up are reserved for operations that generated
+/// code handles itself, such as `FINGERPRINT_OP` and `REFLECT_OP`. These must
+/// match the constants in `idol-runtime`.
+pub const FIRST_RESERVED_OP: u16 = 0xFF00;
+
+/// Operation number that servers answer with their interface fingerprint, if
+/// generated with `Generator::handshake`.
+pub const FINGERPRINT_OP: u16 = 0xFFFF;
+
+/// Operation number that servers answer with their interface definition, if
+/// generated with `Generator::reflection`.
+pub const REFLECT_OP: u16 = 0xFFFE;
 
 /// Definition of an IPC interface.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Interface {
     /// Name of interface. This will be used in generated types, and should
     /// match Rust type name conventions.
@@ -32,10 +46,179 @@ impl Interface {
     ///
     /// The canonical text representation is the Serde representation of
     /// `Interface` as encoded by RON.
-    pub fn from_str(text: &str) -> Result<Self, ron::Error> {
+    pub fn from_str(text: &str) -> Result<Self, crate::Error> {
         let iface: Self = ron::de::from_str(text)?;
         Ok(iface)
     }
+
+    /// Converts an `Interface` into the canonical text representation,
+    /// pretty-printed, with defaulted fields omitted.
+    ///
+    /// Parsing the result with `from_str` produces an equal `Interface`.
+    pub fn to_text(&self) -> String {
+        let config = ron::ser::PrettyConfig::new();
+        // The serializer only fails for types RON can't represent, and we don't
+        // use any of those.
+        let body = ron::ser::to_string_pretty(self, config)
+            .expect("interface should always serialize");
+        // RON can emit struct names, but the option for it moved between
+        // releases; adding the one name we need ourselves is easier.
+        format!("Interface{}\n", body)
+    }
+
+    /// Computes a hash of the interface, so that a client and a server can
+    /// check that they were generated from the same definition.
+    ///
+    /// The hash covers the canonical form of the interface (the same form
+    /// embedded by `SectionEncoding::Compact`), so comments and formatting in
+    /// the source don't affect it. It's 64-bit FNV-1a, which is stable across
+    /// releases and platforms, but not cryptographic.
+    pub fn fingerprint(&self) -> u64 {
+        // The serializer only fails for types RON can't represent, and we don't
+        // use any of those.
+        let canonical = ron::ser::to_string(self)
+            .expect("interface should always serialize");
+        canonical.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+            (hash ^ u64::from(b)).wrapping_mul(0x0000_0100_0000_01b3)
+        })
+    }
+
+    /// Reads and parses the interface definition in the file at `path`.
+    ///
+    /// This returns the source text along with the `Interface`, because
+    /// generated servers embed it.
+    pub fn load(
+        path: impl AsRef<Path>,
+    ) -> Result<(Self, String), crate::Error> {
+        let path = path.as_ref();
+        let text =
+            std::fs::read_to_string(path).map_err(|e| crate::Error::Io {
+                path: path.to_owned(),
+                source: e,
+            })?;
+        let iface = Self::from_str(&text).map_err(|e| e.with_path(path))?;
+        Ok((iface, text))
+    }
+
+    /// Checks for things that parse, but that we can't generate code for.
+    pub fn validate(&self) -> Result<(), crate::Error> {
+        if self.ops.len() >= usize::from(FIRST_RESERVED_OP) {
+            return Err(crate::Error::Validation(format!(
+                "interface {} has {} operations, but operation numbers from \
+                 {:#x} are reserved",
+                self.name,
+                self.ops.len(),
+                FIRST_RESERVED_OP
+            )));
+        }
+        for (opname, op) in &self.ops {
+            let fail = |msg: String| {
+                Err(crate::Error::Validation(format!(
+                    "operation {}.{}: {}",
+                    self.name, opname, msg
+                )))
+            };
+
+            for (argname, arg) in &op.args {
+                if let Err(msg) = arg.validate_constraints() {
+                    return fail(format!("argument {}: {}", argname, msg));
+                }
+            }
+            let Reply::Result { ok, .. } = &op.reply;
+            if ok.is_constrained() {
+                return fail("reply type has argument constraints".into());
+            }
+            if let Some((_, n)) = ok.ty.bounded() {
+                if n == 0 {
+                    return fail("bounded reply type has no room".into());
+                }
+                if ok.recv != RecvStrategy::FromBytes {
+                    return fail("bounded reply type has recv strategy".into());
+                }
+            }
+            for (argname, arg) in &op.args {
+                if arg.ty.bounded().is_some() {
+                    return fail(format!(
+                        "argument {} has bounded type, which only replies can \
+                         have",
+                        argname
+                    ));
+                }
+            }
+            for (leasename, lease) in &op.leases {
+                if lease.ty.bounded().is_some() {
+                    return fail(format!(
+                        "lease {} has bounded type, which only replies can \
+                         have",
+                        leasename
+                    ));
+                }
+            }
+
+            for (leasename, lease) in &op.leases {
+                if !lease.read && !lease.write {
+                    return fail(format!(
+                        "lease {} grants no access",
+                        leasename
+                    ));
+                }
+                if lease.ty.is_str() && lease.write {
+                    return fail(format!(
+                        "lease {} is a str, which can only be read",
+                        leasename
+                    ));
+                }
+                if lease.max_len.is_some() && !lease.ty.appears_unsized() {
+                    return fail(format!(
+                        "lease {} has sized type but also max_len field",
+                        leasename
+                    ));
+                }
+                if lease.min_len.is_some() && !lease.ty.appears_unsized() {
+                    return fail(format!(
+                        "lease {} has sized type but also min_len field",
+                        leasename
+                    ));
+                }
+                if let (Some(min), Some(max)) = (lease.min_len, lease.max_len) {
+                    if min > max {
+                        return fail(format!(
+                            "lease {} has min_len {} greater than max_len {}",
+                            leasename, min, max
+                        ));
+                    }
+                }
+            }
+
+            if !op.allowed_callers.is_empty() && !op.deny_callers.is_empty() {
+                return fail(
+                    "has both allowed_callers and deny_callers".into(),
+                );
+            }
+            if op
+                .allowed_callers
+                .iter()
+                .chain(&op.deny_callers)
+                .any(|name| name.is_empty())
+            {
+                return fail("has an empty caller name".into());
+            }
+
+            if op.idempotent {
+                // An idempotent operation with a read-write lease seems like a
+                // problem, since it could forward half-initialized state from
+                // one instance of the server to the next, potentially
+                // propagating the crash; we could potentially handle it but
+                // for now, we'll reject it.
+                if op.leases.values().any(|lease| lease.read && lease.write) {
+                    return fail(
+                        "idempotent operation with read/write lease".into(),
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 /// Definition of an operation within an `Interface`.
@@ -43,7 +226,7 @@ impl Interface {
 /// Each interface has zero or more operations; operations are assigned
 /// distinguishing numbers (discriminators) starting from 1 (for historical
 /// reasons).
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Operation {
     /// Arguments of the operation that are passed by-value in the kernel-copied
     /// message. If omitted, zero arguments are assumed.
@@ -51,14 +234,14 @@ pub struct Operation {
     /// The order of arguments is significant, it determines the packing order.
     /// Because this means that ergonomics changes to the API can affect runtime
     /// performance, we may want a way to override this eventually (TODO).
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
     pub args: IndexMap<String, AttributedTy>,
     /// Arguments of the operation that are converted into leases. If omitted,
     /// zero leases are assumed.
     ///
     /// The order of leases is significant, as it determines their numerical
     /// index from the server's perspective.
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
     pub leases: IndexMap<String, Lease>,
     /// Expected type of the response.
     pub reply: Reply,
@@ -66,47 +249,78 @@ pub struct Operation {
     /// When `true`, signals that clients should automatically retry this
     /// operation if the server crashes. When `false`, the dead-codes produced
     /// by a crash need to be mapped into the result type.
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "is_false")]
     pub idempotent: bool,
+
+    /// Names of the only tasks that may call this operation. Messages from
+    /// any other task are rejected with `ClientError::AccessDenied` before
+    /// reaching the server implementation. If empty, any task may call it.
+    ///
+    /// Generated servers ask the implementation which task a sender is,
+    /// through a `sender_is_task` method.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_callers: Vec<String>,
+    /// Names of tasks that may not call this operation, as for
+    /// `allowed_callers`. At most one of the two may be given.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deny_callers: Vec<String>,
+}
+
+impl Operation {
+    /// Checks whether calls to this operation are restricted by
+    /// `allowed_callers` or `deny_callers`.
+    pub fn restricts_callers(&self) -> bool {
+        !self.allowed_callers.is_empty() || !self.deny_callers.is_empty()
+    }
 }
 
 /// Description of a lease expected by an operation.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Lease {
-    /// Type being leased.
+    /// Type being leased. This can be `str`, for a read-only lease of UTF-8
+    /// text.
     #[serde(rename = "type")]
     pub ty: Ty,
     /// The server will be able to read from this lease. The type being leased
     /// must implement `zerocopy::AsBytes`.
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "is_false")]
     pub read: bool,
     /// The server will be able to write to this lease. The type being leased
     /// must implement both `zerocopy::AsBytes` and `zerocopy::FromBytes`.
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "is_false")]
     pub write: bool,
     /// The server cannot accept leases longer than this.
     ///
     /// This is only meaningful if `ty` is unsized, which at the moment means a
-    /// slice. It is measured in number of elements in the slice, _not_ number
-    /// of bytes.
+    /// slice or `str`. It is measured in number of elements in the slice, _not_
+    /// number of bytes, except for `str`, where it's in bytes.
     ///
     /// It's limited to `u32` rather than `usize` because in the current kernel
     /// ABI, individual leases are limited to 4GiB. This also means that, if
     /// this value is not provided, the limit defaults to 4GiB.
     ///
     /// If provided, the value cannot be zero.
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub max_len: Option<NonZeroU32>,
+    /// The server cannot accept leases shorter than this, e.g. because it
+    /// always reads a fixed-size header.
+    ///
+    /// Like `max_len`, this is only meaningful for slices and `str`, and is
+    /// measured in elements (bytes, for `str`). If provided, the value cannot
+    /// be zero, and cannot be greater than `max_len`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_len: Option<NonZeroU32>,
 }
 
 /// Potential packings of reply types into the Hubris IPC reply format.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Reply {
     /// The operation may fail with an error type. This method assumes that
     /// success is indicated by rc=0, and all other values are errors.
     Result {
         /// On success (rc=0), the reply buffer will be interpreted as this
-        /// type.
+        /// type. A bounded type, `[T; ..N]`, means a reply of up to `N`
+        /// values of `T`, of which only those present are sent.
         ok: AttributedTy,
         /// On failure (rc != 0), the given strategy will kick in.
         err: Error,
@@ -124,22 +338,93 @@ pub enum Reply {
 ///
 /// If it's written as a raw type name, the attributes (other fields in this
 /// struct) are all defaulted.
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct AttributedTy {
     /// Name of type.
-    #[serde(rename = "type")]
     pub ty: Ty,
     /// How to unpack this type when it is received from another task, either as
     /// an incoming argument, or as a reply.
-    #[serde(default)]
     pub recv: RecvStrategy,
+
+    /// For arguments, the inclusive range of values that servers accept.
+    ///
+    /// This and the other constraints apply to the value as it's sent, before
+    /// any conversion by `recv`, and servers reject messages that break them
+    /// with `ClientError::BadMessage` before calling the implementation.
+    /// `range` and `one_of` only apply to integers.
+    pub range: Option<(i64, i64)>,
+    /// For arguments, the only values that servers accept. If empty, any value
+    /// is accepted.
+    pub one_of: Vec<i64>,
+    /// For arguments, the name of a function that servers call with a
+    /// reference to the value, and that returns whether to accept it.
+    pub validate: Option<String>,
 }
 
 impl AttributedTy {
+    /// Creates an `AttributedTy` for `ty`, with every attribute defaulted.
+    pub fn new(ty: Ty) -> Self {
+        Self {
+            ty,
+            recv: RecvStrategy::default(),
+            range: None,
+            one_of: vec![],
+            validate: None,
+        }
+    }
+
+    /// Checks whether any of `range`, `one_of` or `validate` are given.
+    pub fn is_constrained(&self) -> bool {
+        self.range.is_some()
+            || !self.one_of.is_empty()
+            || self.validate.is_some()
+    }
+
     pub fn display(&self) -> &impl std::fmt::Display {
         &self.ty.0
     }
 
+    /// Checks that `range` and `one_of`, if given, make sense for the type.
+    fn validate_constraints(&self) -> Result<(), String> {
+        if self.range.is_none() && self.one_of.is_empty() {
+            return Ok(());
+        }
+        let ty = self.repr_ty().0.as_str();
+        let unsigned = match ty {
+            "u8" | "u16" | "u32" | "u64" | "u128" | "usize" => true,
+            "i8" | "i16" | "i32" | "i64" | "i128" | "isize" => false,
+            _ => {
+                return Err(format!(
+                    "range and one_of need an integer type, not {}",
+                    ty
+                ))
+            }
+        };
+        if let Some((lo, hi)) = self.range {
+            if lo > hi {
+                return Err(format!("range ({}, {}) is empty", lo, hi));
+            }
+        }
+        // Bounds are `i64`s, so they can't exceed the limits of anything
+        // wider than 64 bits, and `i128` holds the limits of everything else.
+        let bits = super::layout::primitive_size(ty).unwrap_or(8).min(8) * 8;
+        let (min, max) = if unsigned {
+            (0, (1i128 << bits) - 1)
+        } else {
+            (-(1i128 << (bits - 1)), (1i128 << (bits - 1)) - 1)
+        };
+        let out_of_range = self
+            .range
+            .iter()
+            .flat_map(|&(lo, hi)| [lo, hi])
+            .chain(self.one_of.iter().copied())
+            .find(|&n| !(min..=max).contains(&i128::from(n)));
+        match out_of_range {
+            Some(n) => Err(format!("{} is out of range for {}", n, ty)),
+            None => Ok(()),
+        }
+    }
+
     /// Returns the Rust type that should be used to represent this in the
     /// internal args/reply structs.
     pub fn repr_ty(&self) -> &Ty {
@@ -173,10 +458,17 @@ impl<'de> serde::de::Visitor<'de> for AttributedTyVisitor {
         enum Field {
             Type,
             Recv,
+            Range,
+            #[serde(rename = "one_of")]
+            OneOf,
+            Validate,
         }
 
         let mut ty = None;
         let mut recv = None;
+        let mut range = None;
+        let mut one_of = None;
+        let mut validate = None;
         while let Some(key) = access.next_key()? {
             match key {
                 Field::Type => {
@@ -191,21 +483,81 @@ impl<'de> serde::de::Visitor<'de> for AttributedTyVisitor {
                     }
                     recv = Some(access.next_value()?);
                 }
+                Field::Range => {
+                    if range.is_some() {
+                        return Err(serde::de::Error::duplicate_field("range"));
+                    }
+                    range = Some(access.next_value()?);
+                }
+                Field::OneOf => {
+                    if one_of.is_some() {
+                        return Err(serde::de::Error::duplicate_field(
+                            "one_of",
+                        ));
+                    }
+                    one_of = Some(access.next_value()?);
+                }
+                Field::Validate => {
+                    if validate.is_some() {
+                        return Err(serde::de::Error::duplicate_field(
+                            "validate",
+                        ));
+                    }
+                    validate = Some(access.next_value()?);
+                }
             }
         }
         let ty = ty.ok_or_else(|| serde::de::Error::missing_field("type"))?;
-        let recv = recv.unwrap_or_else(RecvStrategy::default);
-        Ok(AttributedTy { ty, recv })
+        Ok(AttributedTy {
+            recv: recv.unwrap_or_else(RecvStrategy::default),
+            range,
+            one_of: one_of.unwrap_or_default(),
+            validate,
+            ..AttributedTy::new(ty)
+        })
     }
 
     fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
     where
         E: serde::de::Error,
     {
-        Ok(AttributedTy {
-            ty: Ty(v.to_string()),
-            recv: RecvStrategy::default(),
-        })
+        Ok(AttributedTy::new(Ty(v.to_string())))
+    }
+}
+
+/// Serializes an `AttributedTy` in the shortest form that deserializes back to
+/// the same value: a bare type name if all attributes are defaulted.
+impl Serialize for AttributedTy {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeStruct;
+
+        let recv = self.recv != RecvStrategy::default();
+        if !recv && !self.is_constrained() {
+            return self.ty.serialize(serializer);
+        }
+        let len = 1
+            + usize::from(recv)
+            + usize::from(self.range.is_some())
+            + usize::from(!self.one_of.is_empty())
+            + usize::from(self.validate.is_some());
+        let mut s = serializer.serialize_struct("AttributedTy", len)?;
+        s.serialize_field("type", &self.ty)?;
+        if recv {
+            s.serialize_field("recv", &self.recv)?;
+        }
+        if let Some(range) = &self.range {
+            s.serialize_field("range", range)?;
+        }
+        if !self.one_of.is_empty() {
+            s.serialize_field("one_of", &self.one_of)?;
+        }
+        if let Some(validate) = &self.validate {
+            s.serialize_field("validate", validate)?;
+        }
+        s.end()
     }
 }
 
@@ -219,7 +571,7 @@ impl<'de> Deserialize<'de> for AttributedTy {
 }
 
 /// A type name.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(transparent)]
 pub struct Ty(pub String);
 
@@ -229,13 +581,26 @@ impl Ty {
     /// We use this to choose lease validation strategies.
     pub fn appears_unsized(&self) -> bool {
         // This is a hack. Need to work out a better way to determine this.
-        self.0.starts_with('[') && self.0.ends_with(']')
+        (self.0.starts_with('[') && self.0.ends_with(']')) || self.is_str()
+    }
+
+    /// Parses a bounded type, `[T; ..N]`, returning `T` and `N`. These are
+    /// allowed only as the `ok` type of a reply, which then holds up to `N`
+    /// values.
+    pub fn bounded(&self) -> Option<(&str, usize)> {
+        crate::layout::parse_bounded(&self.0)
+    }
+
+    /// Checks whether this is `str`, which leases treat as a slice of bytes
+    /// that must be UTF-8.
+    pub fn is_str(&self) -> bool {
+        self.0.trim() == "str"
     }
 }
 
 /// Enumerates different ways that an error type might be passed through the
 /// REPLY syscall.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Error {
     /// The error type should be created from the (non-zero) return code only.
     /// The reply message in error cases is expected to be zero-length.
@@ -247,7 +612,7 @@ pub enum Error {
 
 /// Enumerates different ways that a type might be unpacked when received over
 /// an IPC interface from another task.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum RecvStrategy {
     /// The received bytes should be directly reinterpreted as the type using
     /// `zerocopy::FromBytes`.
@@ -271,3 +636,8 @@ impl Default for RecvStrategy {
         Self::FromBytes
     }
 }
+
+/// Used to leave `false` flags out of the canonical text representation.
+fn is_false(b: &bool) -> bool {
+    !*b
+}